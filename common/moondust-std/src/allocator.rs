@@ -0,0 +1,73 @@
+//! The global allocator of user programs. Small allocations come from a buddy heap that grows
+//! through [`Heap::expand_heap_by`]. Large allocations get their own mapping. So, their memory
+//! goes back to the kernel when they are freed.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+use moondust_sys::syscall::{heap::Heap, memory::Memory, protection};
+use moondust_utils::buddy_system_allocator::LockedHeapWithRescue;
+
+/// Allocations of at least this size get their own mapping.
+const LARGE_ALLOCATION: usize = 64 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+pub struct UserAllocator<const ORDER: usize> {
+    heap: LockedHeapWithRescue<ORDER>,
+}
+
+impl<const ORDER: usize> UserAllocator<ORDER> {
+    pub const fn new(heap: LockedHeapWithRescue<ORDER>) -> Self {
+        UserAllocator { heap }
+    }
+
+    /// The heap used for small allocations.
+    pub fn heap(&self) -> &LockedHeapWithRescue<ORDER> {
+        &self.heap
+    }
+
+    /// Mappings are page aligned. So, larger alignments are left to the heap.
+    fn is_large(layout: Layout) -> bool {
+        layout.size() >= LARGE_ALLOCATION && layout.align() <= PAGE_SIZE
+    }
+
+    fn mapping_size(layout: Layout) -> usize {
+        (layout.size() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+    }
+}
+
+unsafe impl<const ORDER: usize> GlobalAlloc for UserAllocator<ORDER> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if Self::is_large(layout) {
+            return Memory::map(Self::mapping_size(layout), protection::WRITE)
+                .unwrap_or(ptr::null_mut());
+        }
+
+        unsafe { self.heap.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // Mappings are already zeroed. Writing the zeros would allocate all the pages.
+        if Self::is_large(layout) {
+            return unsafe { self.alloc(layout) };
+        }
+
+        let allocation = unsafe { self.heap.alloc(layout) };
+        if !allocation.is_null() {
+            unsafe { ptr::write_bytes(allocation, 0, layout.size()) };
+        }
+        allocation
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if Self::is_large(layout) {
+            let _ = Memory::unmap(ptr, Self::mapping_size(layout));
+            return;
+        }
+
+        unsafe { self.heap.dealloc(ptr, layout) }
+    }
+}
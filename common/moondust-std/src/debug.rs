@@ -8,8 +8,7 @@ struct DebugPrinter {}
 impl fmt::Write for DebugPrinter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         let scall = Syscalls::Debug { data: s };
-        scall.invoke();
-        Ok(())
+        scall.invoke().map(|_| ()).map_err(|_| fmt::Error)
     }
 }
 
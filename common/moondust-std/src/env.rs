@@ -0,0 +1,98 @@
+//! Arguments and variables of the process. The kernel puts them on the initial stack as
+//! described in [`moondust_sys::auxv`].
+
+use alloc::string::String;
+use core::{
+    ptr, slice,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(ptr::null_mut());
+
+/// Remember where the arguments and the variables are.
+///
+/// # Safety
+/// `stack` must point to the initial stack of the process.
+pub(crate) unsafe fn init(stack: *const u64) {
+    let argc = unsafe { *stack } as usize;
+    let argv = unsafe { stack.add(1) } as *mut *const u8;
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+    ENVP.store(unsafe { argv.add(argc + 1) }, Ordering::Relaxed);
+}
+
+/// Iterator over the arguments of the process. Returned by [`args`].
+#[derive(Debug)]
+pub struct Args {
+    index: usize,
+}
+
+impl Iterator for Args {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if self.index >= ARGC.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let arg = unsafe { read_string(*ARGV.load(Ordering::Relaxed).add(self.index)) };
+        self.index += 1;
+        Some(arg)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = ARGC.load(Ordering::Relaxed).saturating_sub(self.index);
+        (remaining, Some(remaining))
+    }
+}
+
+/// Get the arguments of the process. The first argument is the path of the program.
+pub fn args() -> Args {
+    Args { index: 0 }
+}
+
+/// Iterator over the variables of the process. Returned by [`vars`].
+#[derive(Debug)]
+pub struct Vars {
+    next: *const *const u8,
+}
+
+impl Iterator for Vars {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<(String, String)> {
+        if self.next.is_null() || unsafe { *self.next }.is_null() {
+            return None;
+        }
+
+        let var = unsafe { read_string(*self.next) };
+        self.next = unsafe { self.next.add(1) };
+        match var.split_once('=') {
+            Some((key, value)) => Some((key.into(), value.into())),
+            None => Some((var, String::new())),
+        }
+    }
+}
+
+/// Get the `KEY=VALUE` variables of the process as key and value pairs.
+pub fn vars() -> Vars {
+    Vars {
+        next: ENVP.load(Ordering::Relaxed),
+    }
+}
+
+/// Read the string at `ptr` up to the 0 byte. Invalid UTF-8 is replaced.
+///
+/// # Safety
+/// `ptr` must point to a 0 terminated string.
+unsafe fn read_string(ptr: *const u8) -> String {
+    let mut length = 0;
+    while unsafe { *ptr.add(length) } != 0 {
+        length += 1;
+    }
+
+    let bytes = unsafe { slice::from_raw_parts(ptr, length) };
+    String::from_utf8_lossy(bytes).into_owned()
+}
@@ -1,21 +1,28 @@
 #![no_std]
 #![feature(alloc_error_handler)]
 #![feature(asm)]
+#![deny(unsafe_op_in_unsafe_fn)]
 
 use core::{alloc::Layout, cmp::max, panic::PanicInfo};
 
+use allocator::UserAllocator;
 use moondust_sys::syscall::{heap::Heap, Syscalls};
 use moondust_utils::buddy_system_allocator::{self, LockedHeapWithRescue};
 
+pub mod allocator;
 pub mod debug;
+pub mod env;
+pub mod process;
+pub mod sync;
 pub mod thread;
+pub mod time;
 
 #[macro_use]
 extern crate alloc;
 
 // TODO: Is it 20?
 #[global_allocator]
-pub static HEAP: LockedHeapWithRescue<20> = LockedHeapWithRescue::new(expand_heap);
+pub static HEAP: UserAllocator<20> = UserAllocator::new(LockedHeapWithRescue::new(expand_heap));
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -28,21 +35,26 @@ fn panic(info: &PanicInfo) -> ! {
     debug_print!("User PANIC: {}", info);
 
     let exit_call = Syscalls::Exit(1);
-    exit_call.invoke();
+    let _ = exit_call.invoke();
     unreachable!()
 }
 
+/// Entry point of the process. `stack` points to the initial stack. See
+/// [`moondust_sys::auxv`].
 #[no_mangle]
-fn _start() -> ! {
+extern "C" fn _start(stack: *const u64) -> ! {
+    unsafe { env::init(stack) };
+
     let current_heap_size = Heap::get_current_heap_size();
     unsafe {
-        HEAP.lock()
+        HEAP.heap()
+            .lock()
             .add_to_heap(0x4000_0000_0000, 0x4000_0000_0000 + current_heap_size);
     }
 
     unsafe { asm!("call main") };
     let exit_call = Syscalls::Exit(0);
-    exit_call.invoke();
+    let _ = exit_call.invoke();
     unreachable!()
 }
 
@@ -0,0 +1,72 @@
+use moondust_sys::syscall::{ExitReason, ProcessControl, SyscallError, Syscalls};
+
+/// Duplicate the current process. Only the calling thread is copied and the memory
+/// is shared copy-on-write. Returns the thread id of the new thread in the parent and
+/// `None` in the child.
+pub fn fork() -> Option<u64> {
+    let fork_call = Syscalls::Process(ProcessControl::Fork);
+    match fork_call.invoke() {
+        Ok((0, _)) => None,
+        Ok((thread_id, _)) => Some(thread_id),
+        Err(_) => panic!("Fork failure."),
+    }
+}
+
+/// Start the program at `path` in the initial ram disk in a new process.
+/// Returns the id of the new process.
+pub fn spawn(path: &str) -> Result<u64, SyscallError> {
+    let spawn_call = Syscalls::Process(ProcessControl::Spawn { path });
+    spawn_call.invoke().map(|(process_id, _)| process_id)
+}
+
+/// Kill a process started with [`spawn`].
+pub fn kill(process_id: u64) -> Result<(), SyscallError> {
+    let kill_call = Syscalls::Process(ProcessControl::Kill { process_id });
+    kill_call.invoke().map(|_| ())
+}
+
+/// How a process finished. Returned by [`wait`] and [`try_wait`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The main thread exited with the code.
+    Exited(u8),
+    /// A thread made an invalid memory access at the address.
+    PageFault(u64),
+    /// A thread overflowed its stack at the address.
+    StackOverflow(u64),
+    /// The process was killed to free memory.
+    OutOfMemory,
+    /// The process was killed with [`kill`].
+    Killed,
+}
+
+/// Wait for a process started with [`spawn`] to finish. The process is released after this.
+/// So, it can only be waited for once.
+pub fn wait(process_id: u64) -> Result<ExitStatus, SyscallError> {
+    let wait_call = Syscalls::Process(ProcessControl::Wait { process_id });
+    let (reason, value) = wait_call.invoke()?;
+    to_exit_status(reason, value).ok_or(SyscallError::Failed)
+}
+
+/// Like [`wait`] but returns `None` without waiting if the process has not finished yet.
+pub fn try_wait(process_id: u64) -> Result<Option<ExitStatus>, SyscallError> {
+    let wait_call = Syscalls::Process(ProcessControl::TryWait { process_id });
+    let (reason, value) = wait_call.invoke()?;
+    if reason == ExitReason::Running as u64 {
+        return Ok(None);
+    }
+    to_exit_status(reason, value)
+        .map(Some)
+        .ok_or(SyscallError::Failed)
+}
+
+fn to_exit_status(reason: u64, value: u64) -> Option<ExitStatus> {
+    match ExitReason::from_code(reason)? {
+        ExitReason::Exited => Some(ExitStatus::Exited(value as u8)),
+        ExitReason::PageFault => Some(ExitStatus::PageFault(value)),
+        ExitReason::StackOverflow => Some(ExitStatus::StackOverflow(value)),
+        ExitReason::OutOfMemory => Some(ExitStatus::OutOfMemory),
+        ExitReason::Killed => Some(ExitStatus::Killed),
+        ExitReason::Running => None,
+    }
+}
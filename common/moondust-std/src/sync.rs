@@ -0,0 +1,10 @@
+//! Synchronization primitives that block the thread in the kernel instead of spinning.
+
+mod condvar;
+mod futex;
+mod mutex;
+mod once;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use once::Once;
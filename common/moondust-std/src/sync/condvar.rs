@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{
+    futex::{futex_wait, futex_wake},
+    MutexGuard,
+};
+
+/// A condition variable. Threads waiting on it are blocked in the kernel.
+pub struct Condvar {
+    /// Changed on every notification so that waiters can detect them.
+    sequence: AtomicU32,
+}
+
+impl Condvar {
+    /// Creates a new condition variable.
+    pub const fn new() -> Condvar {
+        Condvar {
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// Release the lock and block the thread until this condition variable is notified.
+    /// The lock is acquired again before returning. This can return spuriously.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        drop(guard);
+
+        futex_wait(&self.sequence, sequence);
+        mutex.lock()
+    }
+
+    /// Wake up one thread waiting on this condition variable.
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.sequence, 1);
+    }
+
+    /// Wake up all the threads waiting on this condition variable.
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.sequence, usize::MAX);
+    }
+}
@@ -0,0 +1,15 @@
+use core::sync::atomic::AtomicU32;
+
+use moondust_sys::syscall::{FutexControl, Syscalls};
+
+/// Block the thread while `addr` contains `expected`. This can return spuriously.
+pub fn futex_wait(addr: &AtomicU32, expected: u32) {
+    let wait_call = Syscalls::Futex(FutexControl::Wait { addr, expected });
+    wait_call.invoke().expect("Futex wait failure.");
+}
+
+/// Wake up to `count` threads waiting on `addr`.
+pub fn futex_wake(addr: &AtomicU32, count: usize) {
+    let wake_call = Syscalls::Futex(FutexControl::Wake { addr, count });
+    wake_call.invoke().expect("Futex wake failure.");
+}
@@ -0,0 +1,62 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::futex::{futex_wait, futex_wake};
+
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+/// Running and there are threads waiting for it to complete.
+const WAITING: u32 = 2;
+const COMPLETE: u32 = 3;
+
+/// A synchronization primitive to run a one-time initialization. Threads waiting for the
+/// initialization to complete are blocked in the kernel.
+pub struct Once {
+    state: AtomicU32,
+}
+
+impl Once {
+    /// Creates a new `Once`.
+    pub const fn new() -> Once {
+        Once {
+            state: AtomicU32::new(INCOMPLETE),
+        }
+    }
+
+    /// Returns true if an initialization has completed.
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Run `f` if no other initialization has run. If another thread is running the
+    /// initialization, this blocks until it completes.
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        let mut f = Some(f);
+        loop {
+            match self.state.compare_exchange(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    (f.take().unwrap())();
+                    if self.state.swap(COMPLETE, Ordering::Release) == WAITING {
+                        futex_wake(&self.state, usize::MAX);
+                    }
+                    return;
+                }
+                Err(COMPLETE) => return,
+                Err(RUNNING) => {
+                    // Let the running thread know that it has to wake us up.
+                    let _ = self.state.compare_exchange(
+                        RUNNING,
+                        WAITING,
+                        Ordering::Acquire,
+                        Ordering::Acquire,
+                    );
+                }
+                Err(_) => futex_wait(&self.state, WAITING),
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use alloc::{boxed::Box, sync::Arc};
 use core::cell::UnsafeCell;
-use moondust_sys::syscall::{ProcessControl, Syscalls};
+use moondust_sys::syscall::{ExitKind, ProcessControl, Syscalls};
 
 pub fn spawn<F, T>(f: F) -> JoinHandle<T>
 where
@@ -25,7 +25,7 @@ where
         }
 
         let exit_call = Syscalls::Exit(0);
-        exit_call.invoke();
+        let _ = exit_call.invoke();
     }
 
     let thread_syscall = Syscalls::Process(ProcessControl::CreateThread {
@@ -33,19 +33,33 @@ where
         stack_size: 10 * 1024,
         ip: thread_start as *const () as usize,
     });
-    let result = thread_syscall.invoke();
-    let thread_id = match result {
-        moondust_sys::syscall::Sysrets::SuccessWithVal(thread_id) => thread_id,
-        _ => panic!("Spawn failure."),
-    };
+    let (thread_id, _) = thread_syscall.invoke().expect("Spawn failure.");
 
     JoinHandle {
-        _result: my_result,
-        _thread_id: thread_id,
+        result: my_result,
+        thread_id,
     }
 }
 
+/// An owned permission to join on a thread.
 pub struct JoinHandle<T> {
-    _result: Arc<UnsafeCell<Option<Result<T, ()>>>>,
-    _thread_id: u64,
+    result: Arc<UnsafeCell<Option<Result<T, ()>>>>,
+    thread_id: u64,
+}
+
+impl<T> JoinHandle<T> {
+    /// Wait for the thread to finish and get its result. Returns `Err` if the thread
+    /// panicked or was killed before it could finish.
+    pub fn join(self) -> Result<T, ()> {
+        let join_call = Syscalls::Process(ProcessControl::JoinThread {
+            thread_id: self.thread_id,
+        });
+        match join_call.invoke() {
+            Ok((kind, 0)) if kind == ExitKind::Exited as u64 => {}
+            _ => return Err(()),
+        }
+
+        // The thread has exited. So, nothing else accesses the result anymore.
+        unsafe { (*self.result.get()).take() }.unwrap_or(Err(()))
+    }
 }
@@ -0,0 +1,31 @@
+//! The initial stack of a process.
+//!
+//! The main thread of a new process starts with the stack pointer at `argc`, the same way as
+//! the System V ABI. `rdi` also holds the stack pointer. From lower to higher addresses:
+//!
+//! | Content                                          |
+//! |--------------------------------------------------|
+//! | `argc`                                           |
+//! | `argc` pointers to the arguments, then 0         |
+//! | pointers to the `KEY=VALUE` variables, then 0    |
+//! | key and value pairs of the auxiliary vector      |
+//! | [`AT_NULL`] and 0                                |
+//! | the strings and random bytes that are pointed to |
+//!
+//! All strings end with a 0 byte. The first argument is the path of the program. The stack
+//! pointer is aligned to 16 bytes.
+
+/// Marks the end of the auxiliary vector.
+pub const AT_NULL: u64 = 0;
+/// Address of the program headers of the program.
+pub const AT_PHDR: u64 = 3;
+/// Size of one program header.
+pub const AT_PHENT: u64 = 4;
+/// Number of program headers.
+pub const AT_PHNUM: u64 = 5;
+/// Size of a page.
+pub const AT_PAGESZ: u64 = 6;
+/// Entry point of the program.
+pub const AT_ENTRY: u64 = 9;
+/// Address of 16 random bytes.
+pub const AT_RANDOM: u64 = 25;
@@ -1,4 +1,6 @@
 #![no_std]
 #![feature(asm)]
+#![deny(unsafe_op_in_unsafe_fn)]
 
+pub mod auxv;
 pub mod syscall;
@@ -1,84 +1,383 @@
+//! The syscall interface of the kernel.
+//!
+//! # ABI
+//! A syscall is made with the `syscall` instruction. The syscall number is passed in `rax`
+//! and up to six integer arguments are passed in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`.
+//! On return, `rax` holds the error code (see [`SyscallError`], 0 means success) and `rdi`
+//! and `rsi` hold the returned values. `rcx` and `r11` are clobbered by the CPU. All the other
+//! general purpose registers are preserved.
+//!
+//! # Syscall numbers
+//! Numbers are never reused or changed. New syscalls get a new number and increase
+//! [`SYSCALL_ABI_VERSION`]. This way old user binaries keep working on newer kernels.
+//!
+//! | Number | Name            | Arguments                  | Returns               |
+//! |--------|-----------------|----------------------------|-----------------------|
+//! | 0      | `ABI_VERSION`   | -                          | version               |
+//! | 1      | `EXIT`          | exit code                  | does not return       |
+//! | 2      | `DEBUG`         | string pointer, length     | -                     |
+//! | 3      | `HEAP_SIZE`     | -                          | heap size             |
+//! | 4      | `HEAP_INCREASE` | size                       | start, end of added   |
+//! | 5      | `CREATE_THREAD` | ip, stack size, extra data | thread id             |
+//! | 6      | `FORK`          | -                          | thread and process id |
+//! | 7      | `JOIN_THREAD`   | thread id                  | exit kind, value      |
+//! | 8      | `FUTEX_WAIT`    | address, expected value    | -                     |
+//! | 9      | `FUTEX_WAKE`    | address, count             | number of woken       |
+//! | 10     | `TIME_NOW`      | -                          | seconds, nanoseconds  |
+//! | 11     | `SLEEP_FOR`     | seconds, nanoseconds       | -                     |
+//! | 12     | `MMAP`          | address, size, protection  | start                 |
+//! | 13     | `MUNMAP`        | address, size              | -                     |
+//! | 14     | `MPROTECT`      | address, size, protection  | -                     |
+//! | 15     | `SPAWN`         | path pointer, length       | process id, handle    |
+//! | 16     | `KILL`          | process id                 | -                     |
+//! | 17     | `WAIT`          | process id                 | exit reason, value    |
+//! | 18     | `TRY_WAIT`      | process id                 | exit reason, value    |
+//!
+//! `JOIN_THREAD` returns an [`ExitKind`] and the exit code for [`ExitKind::Exited`] or the
+//! faulting address for [`ExitKind::Killed`]. The address is 0 for threads that were killed
+//! to free memory or by another process.
+//!
+//! `FUTEX_WAIT` blocks the thread until `FUTEX_WAKE` is called on the same address if the
+//! 32 bit word at the address has the expected value. Otherwise, it returns immediately.
+//! It can also return without a wake up. So, the caller must check the word again.
+//!
+//! `TIME_NOW` returns the time of a monotonic clock that starts at boot.
+//!
+//! `MMAP` reserves anonymous zeroed memory at the page aligned address or anywhere if the
+//! address is 0. The pages are only allocated when they are used. The protection is a
+//! combination of the [`protection`] flags. `MUNMAP` and `MPROTECT` only work on memory from
+//! `MMAP` and must be page aligned. They can cover parts of a mapping or several mappings.
+//! `MMAP` and `HEAP_INCREASE` fail with [`SyscallError::InvalidArgument`] for sizes that don't
+//! fit in the address space. `MMAP` also fails for a size of 0.
+//!
+//! `SPAWN` starts the program at the path in the initial ram disk in a new process. The program
+//! gets the path as its only argument and no variables. See [`crate::auxv`]. The new process
+//! is a child of the caller. `KILL` only works on children. Their threads exit the next time
+//! they are scheduled. Threads that are blocked in a syscall are woken up and exit as well.
+//!
+//! `WAIT` blocks until all the threads of a child process have finished and returns an
+//! [`ExitReason`] with the exit code of the main thread or the faulting address. The child is
+//! released after that and its id can be reused. `TRY_WAIT` does the same without blocking and
+//! returns [`ExitReason::Running`] if the child has not finished yet. Children of forks can be
+//! waited for as well. `FORK` returns 0 for both the ids in the child.
+
+use core::{sync::atomic::AtomicU32, time::Duration};
+
 pub mod heap;
+pub mod memory;
+
+/// Version of the syscall ABI described in this module.
+pub const SYSCALL_ABI_VERSION: u64 = 8;
+
+/// Syscall numbers. See the module documentation for the arguments and return values.
+pub mod numbers {
+    pub const ABI_VERSION: u64 = 0;
+    pub const EXIT: u64 = 1;
+    pub const DEBUG: u64 = 2;
+    pub const HEAP_SIZE: u64 = 3;
+    pub const HEAP_INCREASE: u64 = 4;
+    pub const CREATE_THREAD: u64 = 5;
+    pub const FORK: u64 = 6;
+    pub const JOIN_THREAD: u64 = 7;
+    pub const FUTEX_WAIT: u64 = 8;
+    pub const FUTEX_WAKE: u64 = 9;
+    pub const TIME_NOW: u64 = 10;
+    pub const SLEEP_FOR: u64 = 11;
+    pub const MMAP: u64 = 12;
+    pub const MUNMAP: u64 = 13;
+    pub const MPROTECT: u64 = 14;
+    pub const SPAWN: u64 = 15;
+    pub const KILL: u64 = 16;
+    pub const WAIT: u64 = 17;
+    pub const TRY_WAIT: u64 = 18;
+}
+
+/// Protection flags of [`numbers::MMAP`] and [`numbers::MPROTECT`]. Memory is always readable.
+pub mod protection {
+    pub const WRITE: u64 = 1 << 0;
+    pub const EXECUTE: u64 = 1 << 1;
+}
+
+/// How a thread finished. Returned by [`numbers::JOIN_THREAD`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ExitKind {
+    /// The thread exited by itself.
+    Exited = 0,
+    /// The thread was killed by the kernel. For example, because of an invalid memory access.
+    Killed = 1,
+}
+
+/// How a process finished. Returned by [`numbers::WAIT`] and [`numbers::TRY_WAIT`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ExitReason {
+    /// The main thread exited with the exit code in the value.
+    Exited = 0,
+    /// A thread was killed by an invalid memory access at the address in the value.
+    PageFault = 1,
+    /// A thread overflowed its stack. The value is the faulting address.
+    StackOverflow = 2,
+    /// The process was killed to free memory.
+    OutOfMemory = 3,
+    /// The process was killed by its parent.
+    Killed = 4,
+    /// The process has not finished yet. Only returned by [`numbers::TRY_WAIT`].
+    Running = 5,
+}
+
+impl ExitReason {
+    /// Get the reason from the value returned by the kernel.
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            0 => Some(Self::Exited),
+            1 => Some(Self::PageFault),
+            2 => Some(Self::StackOverflow),
+            3 => Some(Self::OutOfMemory),
+            4 => Some(Self::Killed),
+            5 => Some(Self::Running),
+            _ => None,
+        }
+    }
+}
+
+/// Error codes returned by syscalls in `rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// The syscall number is not known to the kernel.
+    UnknownSyscall = 1,
+    /// An argument is not valid. For example, a pointer outside of the process memory.
+    InvalidArgument = 2,
+    /// The syscall was valid but could not be completed.
+    Failed = 3,
+}
+
+impl SyscallError {
+    /// Get the error from the code in `rax`. Unknown codes are reported as
+    /// [`SyscallError::Failed`] so that newer kernels can add error codes.
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            0 => None,
+            1 => Some(Self::UnknownSyscall),
+            2 => Some(Self::InvalidArgument),
+            _ => Some(Self::Failed),
+        }
+    }
+}
+
+/// The values returned by a syscall or the error.
+pub type SyscallResult = Result<(u64, u64), SyscallError>;
 
+/// A syscall with its arguments.
 #[derive(Debug)]
-#[repr(C)]
 pub enum Syscalls<'a> {
+    AbiVersion,
     Exit(u8),
     Debug { data: &'a str },
 
     Heap(HeapControl),
-    Process(ProcessControl),
+    Process(ProcessControl<'a>),
+    Futex(FutexControl<'a>),
+    Time(TimeControl),
+    Memory(MemoryControl),
 }
 
 #[derive(Debug)]
-#[repr(C)]
 pub enum HeapControl {
     GetCurrentHeapSize,
     IncreaseHeapBy(usize),
 }
 
 #[derive(Debug)]
-#[repr(C)]
-pub enum ProcessControl {
+pub enum ProcessControl<'a> {
     CreateThread {
         ip: usize,
         stack_size: usize,
         extra_data: u64,
     },
+    /// Duplicate the current process with only the calling thread. Returns the
+    /// thread id of the new thread to the parent and 0 to the child.
+    Fork,
+    /// Wait for a thread in the current process to finish.
+    JoinThread { thread_id: u64 },
+    /// Start the program at `path` in the initrd in a new process. Returns the process id.
+    Spawn { path: &'a str },
+    /// Kill a child process.
+    Kill { process_id: u64 },
+    /// Wait for a child process to finish.
+    Wait { process_id: u64 },
+    /// Get the exit reason of a child process if it has finished.
+    TryWait { process_id: u64 },
 }
 
 #[derive(Debug)]
-#[repr(C)]
-pub enum Sysrets {
-    NoVal,
-    Fail,
-    SuccessWithVal(u64),
-    SuccessWithVal2(u64, u64),
+pub enum FutexControl<'a> {
+    /// Block until the futex is woken up if `addr` contains `expected`.
+    Wait { addr: &'a AtomicU32, expected: u32 },
+    /// Wake up to `count` threads waiting on `addr`.
+    Wake { addr: &'a AtomicU32, count: usize },
 }
 
 #[derive(Debug)]
-#[repr(C)]
-pub struct SyscallWrapper<'a> {
-    pub call_info: Option<Syscalls<'a>>,
-    pub return_info: Sysrets,
+pub enum TimeControl {
+    /// Get the time of the monotonic clock.
+    Now,
+    /// Block the thread for at least the given duration.
+    SleepFor(Duration),
+}
+
+#[derive(Debug)]
+pub enum MemoryControl {
+    /// Reserve anonymous memory. `addr` is 0 to let the kernel pick the address.
+    Map {
+        addr: usize,
+        size: usize,
+        protection: u64,
+    },
+    /// Remove anonymous memory.
+    Unmap { addr: usize, size: usize },
+    /// Change the protection of anonymous memory.
+    Protect {
+        addr: usize,
+        size: usize,
+        protection: u64,
+    },
 }
 
 impl Syscalls<'_> {
-    /// Invoke the syscall.
-    pub fn invoke(self) -> Sysrets {
-        let mut wrapper = SyscallWrapper {
-            call_info: Some(self),
-            return_info: Sysrets::NoVal,
-        };
-
-        unsafe {
-            #[cfg(target_feature = "sse")]
-            {
-                asm!(
-                    "syscall",
-                    in("rdi") &mut wrapper,
-                    // All caller-saved registers must be marked as clobberred
-                    out("rax") _, out("rcx") _, out("rdx") _, out("rsi") _,
-                    out("r8") _, out("r9") _, out("r10") _, out("r11") _,
-                    out("xmm0") _, out("xmm1") _, out("xmm2") _, out("xmm3") _,
-                    out("xmm4") _, out("xmm5") _, out("xmm6") _, out("xmm7") _,
-                    out("xmm8") _, out("xmm9") _, out("xmm10") _, out("xmm11") _,
-                    out("xmm12") _, out("xmm13") _, out("xmm14") _, out("xmm15") _,
-                )
+    /// Get the syscall number and the arguments.
+    pub fn encode(&self) -> (u64, [u64; 6]) {
+        match self {
+            Syscalls::AbiVersion => (numbers::ABI_VERSION, [0; 6]),
+            Syscalls::Exit(code) => (numbers::EXIT, [*code as u64, 0, 0, 0, 0, 0]),
+            Syscalls::Debug { data } => (
+                numbers::DEBUG,
+                [data.as_ptr() as u64, data.len() as u64, 0, 0, 0, 0],
+            ),
+            Syscalls::Heap(HeapControl::GetCurrentHeapSize) => (numbers::HEAP_SIZE, [0; 6]),
+            Syscalls::Heap(HeapControl::IncreaseHeapBy(size)) => {
+                (numbers::HEAP_INCREASE, [*size as u64, 0, 0, 0, 0, 0])
             }
-
-            #[cfg(not(target_feature = "sse"))]
-            {
-                asm!(
-                    "syscall",
-                    in("rdi") &mut wrapper,
-                    // All caller-saved registers must be marked as clobberred
-                    out("rax") _, out("rcx") _, out("rdx") _, out("rsi") _,
-                    out("r8") _, out("r9") _, out("r10") _, out("r11") _,
-                )
+            Syscalls::Process(ProcessControl::CreateThread {
+                ip,
+                stack_size,
+                extra_data,
+            }) => (
+                numbers::CREATE_THREAD,
+                [*ip as u64, *stack_size as u64, *extra_data, 0, 0, 0],
+            ),
+            Syscalls::Process(ProcessControl::Fork) => (numbers::FORK, [0; 6]),
+            Syscalls::Process(ProcessControl::JoinThread { thread_id }) => {
+                (numbers::JOIN_THREAD, [*thread_id, 0, 0, 0, 0, 0])
+            }
+            Syscalls::Process(ProcessControl::Spawn { path }) => (
+                numbers::SPAWN,
+                [path.as_ptr() as u64, path.len() as u64, 0, 0, 0, 0],
+            ),
+            Syscalls::Process(ProcessControl::Kill { process_id }) => {
+                (numbers::KILL, [*process_id, 0, 0, 0, 0, 0])
+            }
+            Syscalls::Process(ProcessControl::Wait { process_id }) => {
+                (numbers::WAIT, [*process_id, 0, 0, 0, 0, 0])
+            }
+            Syscalls::Process(ProcessControl::TryWait { process_id }) => {
+                (numbers::TRY_WAIT, [*process_id, 0, 0, 0, 0, 0])
+            }
+            Syscalls::Futex(FutexControl::Wait { addr, expected }) => (
+                numbers::FUTEX_WAIT,
+                [*addr as *const AtomicU32 as u64, *expected as u64, 0, 0, 0, 0],
+            ),
+            Syscalls::Futex(FutexControl::Wake { addr, count }) => (
+                numbers::FUTEX_WAKE,
+                [*addr as *const AtomicU32 as u64, *count as u64, 0, 0, 0, 0],
+            ),
+            Syscalls::Time(TimeControl::Now) => (numbers::TIME_NOW, [0; 6]),
+            Syscalls::Time(TimeControl::SleepFor(duration)) => (
+                numbers::SLEEP_FOR,
+                [duration.as_secs(), duration.subsec_nanos() as u64, 0, 0, 0, 0],
+            ),
+            Syscalls::Memory(MemoryControl::Map {
+                addr,
+                size,
+                protection,
+            }) => (
+                numbers::MMAP,
+                [*addr as u64, *size as u64, *protection, 0, 0, 0],
+            ),
+            Syscalls::Memory(MemoryControl::Unmap { addr, size }) => {
+                (numbers::MUNMAP, [*addr as u64, *size as u64, 0, 0, 0, 0])
             }
+            Syscalls::Memory(MemoryControl::Protect {
+                addr,
+                size,
+                protection,
+            }) => (
+                numbers::MPROTECT,
+                [*addr as u64, *size as u64, *protection, 0, 0, 0],
+            ),
         }
+    }
 
-        wrapper.return_info
+    /// Invoke the syscall.
+    pub fn invoke(self) -> SyscallResult {
+        let (number, args) = self.encode();
+        let (code, val0, val1) = unsafe { raw_syscall(number, args) };
+        match SyscallError::from_code(code) {
+            None => Ok((val0, val1)),
+            Some(error) => Err(error),
+        }
     }
 }
+
+/// Make a syscall with the given number and arguments. Returns the error code and the two
+/// values as described in the module documentation.
+///
+/// # Safety
+/// The arguments must be valid for the syscall. For example, pointers must point to memory
+/// that the kernel can read or write.
+pub unsafe fn raw_syscall(number: u64, args: [u64; 6]) -> (u64, u64, u64) {
+    let code: u64;
+    let val0: u64;
+    let val1: u64;
+
+    unsafe {
+        #[cfg(target_feature = "sse")]
+        {
+            asm!(
+                "syscall",
+                inlateout("rax") number => code,
+                inlateout("rdi") args[0] => val0,
+                inlateout("rsi") args[1] => val1,
+                inlateout("rdx") args[2] => _,
+                inlateout("r10") args[3] => _,
+                inlateout("r8") args[4] => _,
+                inlateout("r9") args[5] => _,
+                // rcx and r11 are clobbered by the CPU.
+                out("rcx") _, out("r11") _,
+                out("xmm0") _, out("xmm1") _, out("xmm2") _, out("xmm3") _,
+                out("xmm4") _, out("xmm5") _, out("xmm6") _, out("xmm7") _,
+                out("xmm8") _, out("xmm9") _, out("xmm10") _, out("xmm11") _,
+                out("xmm12") _, out("xmm13") _, out("xmm14") _, out("xmm15") _,
+            )
+        }
+
+        #[cfg(not(target_feature = "sse"))]
+        {
+            asm!(
+                "syscall",
+                inlateout("rax") number => code,
+                inlateout("rdi") args[0] => val0,
+                inlateout("rsi") args[1] => val1,
+                inlateout("rdx") args[2] => _,
+                inlateout("r10") args[3] => _,
+                inlateout("r8") args[4] => _,
+                inlateout("r9") args[5] => _,
+                // rcx and r11 are clobbered by the CPU.
+                out("rcx") _, out("r11") _,
+            )
+        }
+    }
+
+    (code, val0, val1)
+}
@@ -0,0 +1,36 @@
+use super::{MemoryControl, SyscallError, Syscalls};
+
+pub struct Memory;
+
+impl Memory {
+    /// Reserve `size` bytes of zeroed memory anywhere in the address space.
+    /// See [`super::protection`] for the flags.
+    pub fn map(size: usize, protection: u64) -> Result<*mut u8, SyscallError> {
+        let map = Syscalls::Memory(MemoryControl::Map {
+            addr: 0,
+            size,
+            protection,
+        });
+        let (start, _) = map.invoke()?;
+        Ok(start as *mut u8)
+    }
+
+    /// Remove memory reserved with [`Memory::map`].
+    pub fn unmap(addr: *mut u8, size: usize) -> Result<(), SyscallError> {
+        let unmap = Syscalls::Memory(MemoryControl::Unmap {
+            addr: addr as usize,
+            size,
+        });
+        unmap.invoke().map(|_| ())
+    }
+
+    /// Change the protection of memory reserved with [`Memory::map`].
+    pub fn protect(addr: *mut u8, size: usize, protection: u64) -> Result<(), SyscallError> {
+        let protect = Syscalls::Memory(MemoryControl::Protect {
+            addr: addr as usize,
+            size,
+            protection,
+        });
+        protect.invoke().map(|_| ())
+    }
+}
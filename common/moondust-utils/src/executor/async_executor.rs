@@ -827,7 +827,7 @@ impl Drop for Runner<'_> {
 }
 
 /// Steals some items from one queue into another.
-fn steal<T>(src: &BoundedSegQueue<T>, dest: &BoundedSegQueue<T>) {
+pub(crate) fn steal<T>(src: &BoundedSegQueue<T>, dest: &BoundedSegQueue<T>) {
     // Half of `src`'s length rounded up.
     let mut count = (src.len() + 1) / 2;
 
@@ -849,7 +849,7 @@ fn steal<T>(src: &BoundedSegQueue<T>, dest: &BoundedSegQueue<T>) {
 }
 
 /// Runs a closure when dropped.
-struct CallOnDrop<F: Fn()>(F);
+pub(crate) struct CallOnDrop<F: Fn()>(pub(crate) F);
 
 impl<F: Fn()> Drop for CallOnDrop<F> {
     fn drop(&mut self) {
@@ -1,57 +1,368 @@
-use async_task::Task;
-use futures_lite::prelude::*;
+//! A work-stealing executor with task priorities.
+//!
+//! Every core that runs the executor has its own run queues. A task is queued on the core that
+//! wakes it up and idle cores steal tasks from the other cores. Tasks can also be pinned to a
+//! core with [`PriorityExecutor::spawn_on`]. Pinned tasks are never stolen.
+//!
+//! Tasks with better priorities are run first. To avoid starving the worse priorities, a
+//! waiting task ages every time a task with a better priority is run instead. Once it is
+//! skipped `aging_limit` times, it is run before the better priorities.
 
-use super::async_executor::Executor;
+use alloc::{sync::Arc, vec::Vec};
+use async_task::{Runnable, Task};
+use conquer_once::spin::OnceCell;
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Poll, Waker},
+};
+use futures_lite::{future, prelude::*};
+use spin::{Mutex, RwLock};
+use vec_arena::Arena;
 
-/// An executor with task priorities.
-///
-/// Tasks with lower priorities only get polled when there are no tasks with higher priorities.
+use super::async_executor::{steal, CallOnDrop};
+use crate::bounded_segqueue::BoundedSegQueue;
+
+/// Number of tasks run by a core before [`PriorityExecutor::run`] yields to its caller.
+const TASKS_PER_YIELD: usize = 64;
+
+/// An executor with task priorities. See the module documentation for the scheduling rules.
 pub struct PriorityExecutor<'a, const PRIORTY_COUNT: usize> {
-    ex: [Executor<'a>; PRIORTY_COUNT],
+    state: OnceCell<Arc<State>>,
+
+    /// Returns the index of the current core. Indices should start from 0 and be dense because
+    /// queues are created for every index up to the largest one.
+    current_cpu: fn() -> usize,
+
+    /// Number of times a waiting task can be skipped for tasks with better priorities.
+    aging_limit: usize,
+
+    /// Makes the `'a` lifetime invariant.
+    _marker: PhantomData<core::cell::UnsafeCell<&'a ()>>,
 }
 
-// TODO: Remove the where clause once Default supports arbitarary length.
-impl<'a, const PRIORTY_COUNT: usize> PriorityExecutor<'a, PRIORTY_COUNT>
-where
-    [Executor<'a>; PRIORTY_COUNT]: Default,
-{
-    pub const fn const_new() -> PriorityExecutor<'static, PRIORTY_COUNT> {
-        const VAL: Executor<'static> = Executor::new();
+unsafe impl<const PRIORTY_COUNT: usize> Send for PriorityExecutor<'_, PRIORTY_COUNT> {}
+unsafe impl<const PRIORTY_COUNT: usize> Sync for PriorityExecutor<'_, PRIORTY_COUNT> {}
+
+impl<'a, const PRIORTY_COUNT: usize> PriorityExecutor<'a, PRIORTY_COUNT> {
+    /// Creates a new executor. `aging_limit` is the number of times a waiting task can be
+    /// skipped for tasks with better priorities. 0 disables aging and the priorities are strict.
+    pub const fn const_new(
+        current_cpu: fn() -> usize,
+        aging_limit: usize,
+    ) -> PriorityExecutor<'static, PRIORTY_COUNT> {
         PriorityExecutor {
-            ex: [VAL; PRIORTY_COUNT],
+            state: OnceCell::uninit(),
+            current_cpu,
+            aging_limit,
+            _marker: PhantomData,
         }
     }
 
-    /// Creates a new executor.
-    pub fn new() -> Self {
+    /// Creates a new executor. See [`PriorityExecutor::const_new`].
+    pub fn new(current_cpu: fn() -> usize, aging_limit: usize) -> Self {
         PriorityExecutor {
-            ex: Default::default(),
+            state: OnceCell::uninit(),
+            current_cpu,
+            aging_limit,
+            _marker: PhantomData,
         }
     }
 
     /// Spawns a task with the given priority. Lower numbers have better priority.
+    /// The task can run on any core.
     pub fn spawn<T: Send + 'a>(
         &self,
         priority: usize,
         future: impl Future<Output = T> + Send + 'a,
     ) -> Task<T> {
         assert!(priority < PRIORTY_COUNT);
-        self.ex[priority as usize].spawn(future)
+        let state = self.state().clone();
+        let current_cpu = self.current_cpu;
+        self.spawn_with(future, move |runnable| {
+            state.schedule_shared(current_cpu(), priority, runnable)
+        })
+    }
+
+    /// Spawns a task with the given priority that only runs on the core with index `cpu`.
+    pub fn spawn_on<T: Send + 'a>(
+        &self,
+        cpu: usize,
+        priority: usize,
+        future: impl Future<Output = T> + Send + 'a,
+    ) -> Task<T> {
+        assert!(priority < PRIORTY_COUNT);
+        let state = self.state().clone();
+        self.spawn_with(future, move |runnable| {
+            state.schedule_pinned(cpu, priority, runnable)
+        })
     }
 
-    /// Runs the executor forever.
+    /// Runs the executor on the current core forever.
     pub async fn run(&self) -> ! {
-        //TODO: parametrize over PRIORITY_COUNT
+        let state = self.state();
+        let cpu = (self.current_cpu)();
+        let queues = state.cpu(cpu);
+
         loop {
-            let t0 = self.ex[0].run_n_loops(1);
-            let t1 = self.ex[1].run_n_loops(1);
-            let t2 = self.ex[2].run_n_loops(1);
-            let t3 = self.ex[3].run_n_loops(1);
-            let t4 = self.ex[4].run_n_loops(1);
-
-            // Wait until one of the ticks completes, trying them in order from highest
-            // priority to lowest priority.
-            t0.or(t1).or(t2).or(t3).or(t4).await;
+            for _ in 0..TASKS_PER_YIELD {
+                let runnable = state.runnable(cpu, &queues).await;
+                runnable.run();
+            }
+
+            // Let the caller do its own work now and then.
+            future::yield_now().await;
+        }
+    }
+
+    fn spawn_with<T: Send + 'a>(
+        &self,
+        future: impl Future<Output = T> + Send + 'a,
+        schedule: impl Fn(Runnable) + Send + Sync + 'static,
+    ) -> Task<T> {
+        let mut active = self.state().active.lock();
+
+        // Remove the task from the set of active tasks when the future finishes.
+        let index = active.next_vacant();
+        let state = self.state().clone();
+        let future = async move {
+            let _guard = CallOnDrop(move || drop(state.active.lock().remove(index)));
+            future.await
+        };
+
+        // Create the task and register it in the set of active tasks.
+        let (runnable, task) = unsafe { async_task::spawn_unchecked(future, schedule) };
+        active.insert(runnable.waker());
+
+        runnable.schedule();
+        task
+    }
+
+    /// Returns a reference to the inner state.
+    fn state(&self) -> &Arc<State> {
+        self.state
+            .get_or_init(|| Arc::new(State::new(PRIORTY_COUNT, self.aging_limit)))
+    }
+}
+
+impl<const PRIORTY_COUNT: usize> Drop for PriorityExecutor<'_, PRIORTY_COUNT> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.get() {
+            let mut active = state.active.lock();
+            for i in 0..active.capacity() {
+                if let Some(w) = active.remove(i) {
+                    w.wake();
+                }
+            }
+            drop(active);
+
+            for queues in state.cpus.read().iter() {
+                for queue in queues.shared.iter().chain(queues.pinned.iter()) {
+                    while queue.pop().is_some() {}
+                }
+            }
+        }
+    }
+}
+
+/// The state of a executor.
+#[derive(Debug)]
+struct State {
+    priority_count: usize,
+
+    /// See [`PriorityExecutor::const_new`].
+    aging_limit: usize,
+
+    /// Run queues of the cores indexed by the core index.
+    cpus: RwLock<Vec<Arc<CpuQueues>>>,
+
+    /// Currently active tasks.
+    active: Mutex<Arena<Waker>>,
+}
+
+/// Run queues of a single core.
+#[derive(Debug)]
+struct CpuQueues {
+    /// Tasks that can run on any core. One queue per priority.
+    shared: Vec<BoundedSegQueue<Runnable>>,
+
+    /// Tasks that can only run on this core. One queue per priority.
+    pinned: Vec<BoundedSegQueue<Runnable>>,
+
+    /// Waker of the runner of this core when it is sleeping and not notified yet.
+    sleeper: Mutex<Option<Waker>>,
+
+    /// Set while the runner of this core has nothing to run.
+    idle: AtomicBool,
+
+    /// Number of times tasks with better priorities were run by this core while tasks of the
+    /// priority were waiting. One counter per priority.
+    skipped: Vec<AtomicUsize>,
+}
+
+impl CpuQueues {
+    fn new(priority_count: usize) -> Self {
+        CpuQueues {
+            shared: (0..priority_count)
+                .map(|_| BoundedSegQueue::unbounded())
+                .collect(),
+            pinned: (0..priority_count)
+                .map(|_| BoundedSegQueue::unbounded())
+                .collect(),
+            sleeper: Mutex::new(None),
+            idle: AtomicBool::new(false),
+            skipped: (0..priority_count).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    /// Wakes up the runner of this core. Returns `false` if it was not sleeping or was
+    /// already notified.
+    fn notify(&self) -> bool {
+        let waker = self.sleeper.lock().take();
+        match waker {
+            Some(w) => {
+                w.wake();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl State {
+    fn new(priority_count: usize, aging_limit: usize) -> State {
+        State {
+            priority_count,
+            aging_limit,
+            cpus: RwLock::new(Vec::new()),
+            active: Mutex::new(Arena::new()),
+        }
+    }
+
+    /// Returns the queues of the core with index `cpu`.
+    fn cpu(&self, cpu: usize) -> Arc<CpuQueues> {
+        if let Some(queues) = self.cpus.read().get(cpu) {
+            return queues.clone();
+        }
+
+        let mut cpus = self.cpus.write();
+        while cpus.len() <= cpu {
+            cpus.push(Arc::new(CpuQueues::new(self.priority_count)));
+        }
+        cpus[cpu].clone()
+    }
+
+    /// Queues a task that can run on any core on the core with index `cpu`.
+    fn schedule_shared(&self, cpu: usize, priority: usize, runnable: Runnable) {
+        let queues = self.cpu(cpu);
+        queues.shared[priority].push(runnable).unwrap();
+        queues.notify();
+
+        // The core might be busy. So, let an idle core steal the task.
+        let cpus = self.cpus.read();
+        for (index, other) in cpus.iter().enumerate() {
+            if index != cpu && other.idle.load(Ordering::SeqCst) && other.notify() {
+                break;
+            }
+        }
+    }
+
+    /// Queues a task that can only run on the core with index `cpu`.
+    fn schedule_pinned(&self, cpu: usize, priority: usize, runnable: Runnable) {
+        let queues = self.cpu(cpu);
+        queues.pinned[priority].push(runnable).unwrap();
+        queues.notify();
+    }
+
+    /// Waits for the next task to run on the core with index `cpu`.
+    async fn runnable(&self, cpu: usize, queues: &CpuQueues) -> Runnable {
+        future::poll_fn(|cx| {
+            queues.idle.store(false, Ordering::SeqCst);
+            if let Some(r) = self.find_runnable(cpu, queues) {
+                return Poll::Ready(r);
+            }
+
+            // Register the waker before searching again so that a task queued in between is
+            // not missed.
+            *queues.sleeper.lock() = Some(cx.waker().clone());
+            queues.idle.store(true, Ordering::SeqCst);
+            match self.find_runnable(cpu, queues) {
+                Some(r) => {
+                    queues.idle.store(false, Ordering::SeqCst);
+                    queues.sleeper.lock().take();
+                    Poll::Ready(r)
+                }
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Finds the next task for the core with index `cpu`. This is the task with the best
+    /// priority unless a task with a worse priority waited for too long.
+    fn find_runnable(&self, cpu: usize, queues: &CpuQueues) -> Option<Runnable> {
+        if self.aging_limit > 0 {
+            // The worst priorities have waited the longest.
+            for priority in (1..self.priority_count).rev() {
+                if queues.skipped[priority].load(Ordering::SeqCst) >= self.aging_limit {
+                    queues.skipped[priority].store(0, Ordering::SeqCst);
+                    if let Some(r) = self.pop(cpu, queues, priority) {
+                        self.age(queues, priority);
+                        return Some(r);
+                    }
+                }
+            }
+        }
+
+        for priority in 0..self.priority_count {
+            if let Some(r) = self.pop(cpu, queues, priority) {
+                queues.skipped[priority].store(0, Ordering::SeqCst);
+                self.age(queues, priority);
+                return Some(r);
+            }
+        }
+
+        None
+    }
+
+    /// Takes a task with the given priority for the core with index `cpu`. Tasks of other
+    /// cores are stolen if this core has none.
+    fn pop(&self, cpu: usize, queues: &CpuQueues, priority: usize) -> Option<Runnable> {
+        if let Some(r) = queues.pinned[priority].pop() {
+            return Some(r);
+        }
+
+        if let Some(r) = queues.shared[priority].pop() {
+            return Some(r);
+        }
+
+        // Start with the next core so that the cores don't all steal from the same one.
+        let cpus = self.cpus.read();
+        let count = cpus.len();
+        for other in cpus.iter().cycle().skip(cpu + 1).take(count.saturating_sub(1)) {
+            steal(&other.shared[priority], &queues.shared[priority]);
+            if let Some(r) = queues.shared[priority].pop() {
+                return Some(r);
+            }
+        }
+
+        None
+    }
+
+    /// Ages the waiting tasks with priorities worse than `priority` after a task with
+    /// `priority` is run.
+    fn age(&self, queues: &CpuQueues, priority: usize) {
+        if self.aging_limit == 0 {
+            return;
+        }
+
+        let cpus = self.cpus.read();
+        for worse in priority + 1..self.priority_count {
+            let waiting = !queues.pinned[worse].is_empty()
+                || cpus.iter().any(|other| !other.shared[worse].is_empty());
+            if waiting {
+                queues.skipped[worse].fetch_add(1, Ordering::SeqCst);
+            }
         }
     }
 }
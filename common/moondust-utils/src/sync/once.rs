@@ -34,9 +34,14 @@ impl<T> AsyncOnce<T> {
         self.state.is_completed()
     }
 
+    /// Get the result without waiting. Returns `None` if it is not set yet.
+    pub fn try_get(&self) -> Option<Arc<T>> {
+        self.state.get().cloned()
+    }
+
     pub fn try_set_result(&self, result: T) {
         self.state.call_once(|| Arc::new(result));
-        for waker in self.wakers.pop() {
+        while let Some(waker) = self.wakers.pop() {
             waker.wake();
         }
     }
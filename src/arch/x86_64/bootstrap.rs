@@ -1,18 +1,18 @@
 use core::{
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
     usize,
 };
 
 use super::{globals, memory, PHYSICAL_MEMORY_ALLOCATOR};
 use crate::{
     arch::cpu_locals,
-    common::memory::paging::{IMemoryMapper, MapperPermissions},
+    common::memory::paging::{IMemoryMapper, MapperHints, MapperPermissions},
 };
 use globals::MEM_MAP_OFFSET_LOCATION;
 use log::LevelFilter;
 use x86_64::{
     align_down,
-    registers::{control::Cr4Flags, model_specific::EferFlags},
+    registers::model_specific::EferFlags,
     structures::paging::{page_table::PageTableEntry, OffsetPageTable, PageTableFlags, Translate},
     PhysAddr, VirtAddr,
 };
@@ -31,6 +31,10 @@ const ZERO_ATOMIC64: AtomicU64 = AtomicU64::new(0);
 static KERNEL_AP_STACKS: [AtomicU64; globals::MAX_CORE_COUNT] =
     [ZERO_ATOMIC64; globals::MAX_CORE_COUNT];
 
+/// Next value for [`cpu_locals::CPU_INDEX`]. The BSP always gets 0 because the APs wait for
+/// their stacks which are created after the BSP is initialized.
+static NEXT_CPU_INDEX: AtomicUsize = AtomicUsize::new(0);
+
 pub fn initialize_bootstrap_core() -> ! {
     // Pages for initial bootstrapping. This acts as an intermediate step.
     // We need this for setting up for the main stacks but the bootloader only provdes 1K in memory.
@@ -66,11 +70,7 @@ pub fn initialize_ap_core(core_num: usize) -> ! {
         }
     }
 
-    {
-        let mut cr4 = x86_64::registers::control::Cr4::read();
-        cr4 |= Cr4Flags::PAGE_GLOBAL;
-        cr4 |= Cr4Flags::PCID;
-    }
+    memory::pcid::enable();
 
     super::gdt::load_global_gdt();
 
@@ -105,11 +105,7 @@ fn initialize_bootstrap_core2() -> ! {
         }
     }
 
-    {
-        let mut cr4 = x86_64::registers::control::Cr4::read();
-        cr4 |= Cr4Flags::PAGE_GLOBAL;
-        cr4 |= Cr4Flags::PCID;
-    }
+    memory::pcid::enable();
 
     {
         info!(target: "bootstrap", "Create offset mapping");
@@ -190,6 +186,7 @@ fn initialize_bootstrap_core2() -> ! {
             globals::KERNEL_HEAP_START as *const u8,
             globals::KERNEL_HEAP_SIZE_INITIAL,
             MapperPermissions::WRITE,
+            MapperHints::HUGE_2MIB,
         )
         .expect("Kernel heap init failure");
 
@@ -215,6 +212,7 @@ fn initialize_bootstrap_core2() -> ! {
     {
         info!(target: "bootstrap", "Initializing TLS");
         memory::cpu_local::initialize_tls();
+        cpu_locals::CPU_INDEX.set(NEXT_CPU_INDEX.fetch_add(1, Ordering::SeqCst));
         info!(target: "bootstrap", "TLS Initialized");
     }
 
@@ -270,6 +268,7 @@ fn initialize_ap_core_2() -> ! {
     {
         info!(target: "bootstrap_ap", "Initializing TLS");
         memory::cpu_local::initialize_tls();
+        cpu_locals::CPU_INDEX.set(NEXT_CPU_INDEX.fetch_add(1, Ordering::SeqCst));
         info!(target: "bootstrap_ap", "TLS Initialized");
     }
 
@@ -296,6 +295,7 @@ fn initialize_ap_core_2() -> ! {
 }
 
 fn initialize_bootstrap_core3() -> ! {
+    memory::save_kernel_only_table();
     info!(target: "bootstrap", "CPU Core ready. Is BSP: true, Core ID: {}", cpu_locals::PROCESSOR_ID.get());
     crate::main_bsp();
 }
@@ -3,7 +3,7 @@ use x86_64::{
         segmentation::{load_ss, set_cs},
         tables::load_tss,
     },
-    registers::rflags::RFlags,
+    registers::{model_specific::KernelGsBase, rflags::RFlags},
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
@@ -22,6 +22,13 @@ pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
 static DOUBLE_FAULT_STACK: [u8; SPECIAL_STACK_SIZES] = [0; SPECIAL_STACK_SIZES]; // TODO: Stack Protection.
 #[thread_local]
 static PRIVILEGE_0_STACK: [u8; SPECIAL_STACK_SIZES] = [0; SPECIAL_STACK_SIZES]; // TODO: Stack Protection.
+#[thread_local]
+static SYSCALL_STACK: [u8; SPECIAL_STACK_SIZES] = [0; SPECIAL_STACK_SIZES]; // TODO: Stack Protection.
+
+/// Per core data of the syscall entry. The kernel GS base points here so that the entry can
+/// reach it with `swapgs` before it has a kernel stack.
+#[thread_local]
+static mut SYSCALL_STACKS: SyscallStacks = SyscallStacks::new();
 
 #[thread_local]
 static mut TSS: TaskStateSegment = TaskStateSegment::new();
@@ -34,6 +41,25 @@ static mut GLOBAL_GDT: GlobalDescriptorTable = GlobalDescriptorTable::new(); //
 static mut SELECTORS: SegmentSelectors = SegmentSelectors::new();
 static mut GLOBAL_SELECTORS: SegmentSelectors = SegmentSelectors::new();
 
+/// Stack pointers used by the syscall entry. The layout is `#[repr(C)]` because the entry
+/// reads the fields by their offsets.
+#[repr(C)]
+pub struct SyscallStacks {
+    /// Top of the kernel stack that the syscall entry switches to. Like rsp0 of the TSS.
+    pub kernel_rsp: u64,
+    /// The user stack pointer while the entry switches the stacks.
+    pub user_rsp: u64,
+}
+
+impl SyscallStacks {
+    pub const fn new() -> SyscallStacks {
+        SyscallStacks {
+            kernel_rsp: 0,
+            user_rsp: 0,
+        }
+    }
+}
+
 #[derive(Clone)]
 struct SegmentSelectors {
     kernel_code_selector: SegmentSelector,
@@ -61,6 +87,8 @@ pub fn initialize_gdt() {
         TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
             VirtAddr::new(get_stack_align_for_array(&DOUBLE_FAULT_STACK));
         TSS.privilege_stack_table[0] = VirtAddr::new(get_stack_align_for_array(&PRIVILEGE_0_STACK));
+        SYSCALL_STACKS.kernel_rsp = get_stack_align_for_array(&SYSCALL_STACK);
+        KernelGsBase::write(VirtAddr::from_ptr(&SYSCALL_STACKS as *const SyscallStacks));
 
         let mut gdt = GlobalDescriptorTable::new();
         let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
@@ -109,6 +137,11 @@ pub fn load_global_gdt() {
     }
 }
 
+/// Get the user code and data selectors of the current core.
+pub fn get_user_selectors() -> (SegmentSelector, SegmentSelector) {
+    unsafe { (SELECTORS.user_code_selector, SELECTORS.user_data_selector) }
+}
+
 fn get_stack_align_for_array<'a>(array: &'a [u8]) -> u64 {
     let last_entry_addr = &array[array.len() - 1] as *const u8 as usize;
     let high_aligned_addr = align_down(last_entry_addr, globals::STACK_ALIGN);
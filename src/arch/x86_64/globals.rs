@@ -20,6 +20,10 @@ pub const KERNEL_STACK_MAX_SIZE: usize = 10 * 1024 * 1024;
 pub const KERNEL_STACK_GAP: usize = 0x100_0000;
 pub const KERNEL_STACK_TOTAL_SIZE: usize = 10 * 1024 * 1024 * 1024;
 
+/// Interval between two LAPIC timer interrupts. This is the time slice of user threads and
+/// the resolution of sleeps.
+pub const TIMER_INTERVAL_MS: u32 = 10;
+
 /// Number of cores to suppoer
 pub const MAX_CORE_COUNT: usize = 512;
 
@@ -28,6 +32,13 @@ pub const DEFAULT_LOG_LEVEL: Level = Level::Info;
 pub const EXTRA_LOGS: [&'static str; 1] = ["bootstrap"];
 
 pub const USER_STACK_END: usize = 0x6FFF_FFFF_FFFF;
+/// Minimum virtual memory reserved for a user stack. Pages are only mapped when used.
+pub const USER_STACK_MIN_RESERVE: usize = 8 * 1024 * 1024;
+/// Size of the guard area under each user stack.
+pub const USER_STACK_GUARD_SIZE: usize = 2 * PAGE_SIZE;
 pub const USER_HEAP_START: usize = 0x4000_0000_0000;
 pub const USER_HEAP_END: usize = 0x4FFF_FFFF_FFFF;
 pub const USER_HEAP_DEFAULT_SIZE: usize = 10 * 4096;
+/// Region for anonymous mappings that are placed by the kernel.
+pub const USER_MMAP_START: usize = 0x5000_0000_0000;
+pub const USER_MMAP_END: usize = 0x5FFF_FFFF_FFFF;
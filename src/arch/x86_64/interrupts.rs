@@ -6,7 +6,12 @@ use x86_64::{
 
 use super::gdt;
 use super::globals;
+use super::process::state::PageFault;
 use crate::common::devices::acpi::MemoryHandler;
+use context::InterruptedContext;
+
+#[macro_use]
+pub mod context;
 
 pub mod apic;
 pub mod keyboard;
@@ -22,6 +27,10 @@ pub enum InterruptIndex {
     Spurious,
     Error,
     HpetTimer, // 36
+    /// Inter-processor interrupt that wakes up a halted core. See [`apic::Ipi::Wakeup`].
+    Wakeup,
+    /// Inter-processor interrupt that flushes the TLB. See [`apic::Ipi::TlbShootdown`].
+    TlbShootdown,
 }
 
 impl InterruptIndex {
@@ -43,11 +52,19 @@ pub fn initialize_idt() {
         IDT.double_fault
             .set_handler_fn(double_fault_handler)
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX as u16);
-        IDT.page_fault.set_handler_fn(page_fault_handler);
+        // Page fault and timer handlers are naked, so they are transmuted into the interrupt ABI here.
+        IDT.page_fault.set_handler_fn(core::mem::transmute(
+            page_fault_handler as unsafe extern "C" fn(),
+        ));
 
         IDT[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard::keyboard_handler);
-        IDT[InterruptIndex::Timer.as_usize()].set_handler_fn(timer::timer_handler);
+        IDT[InterruptIndex::Timer.as_usize()].set_handler_fn(core::mem::transmute(
+            timer::timer_handler as unsafe extern "C" fn(),
+        ));
 
+        IDT[InterruptIndex::Wakeup.as_usize()].set_handler_fn(apic::wakeup_handler);
+        IDT[InterruptIndex::TlbShootdown.as_usize()]
+            .set_handler_fn(super::memory::tlb::tlb_shootdown_handler);
         IDT.general_protection_fault.set_handler_fn(unhandled_fault);
         IDT.invalid_opcode.set_handler_fn(unhandled_fault_noerr);
 
@@ -151,17 +168,29 @@ extern "x86-interrupt" fn double_fault_handler(
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+context_saving_handler!(page_fault_handler, page_fault_handler_inner, "");
+
+extern "C" fn page_fault_handler_inner(context: &mut InterruptedContext) {
     use x86_64::registers::control::Cr2;
+    let address = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+
+    // Faults from user mode are resolved in the thread's task. This call doesn't return.
+    if context.is_user_mode() {
+        let fault = PageFault {
+            address: address.as_u64(),
+            ip: context.rip,
+            error_code,
+        };
+        super::process::user_future::fault_user_thread(context, fault);
+    }
+
     error!(
         target: "PageFaultHandler",
-        "EXCEPTION: PAGE FAULT\r\n{:#?}\r\nError Code: {:?}\r\nAccessed Address: {:?}",
-        stack_frame,
+        "EXCEPTION: PAGE FAULT\r\n{:#x?}\r\nError Code: {:?}\r\nAccessed Address: {:?}",
+        context,
         error_code,
-        Cr2::read()
+        address
     );
     loop {
         x86_64::instructions::hlt();
@@ -1,12 +1,33 @@
 //! LAPIC and IOAPIC support for x86_64 architecture.
 
-use core::{cell::Cell, ptr::null_mut, usize};
+use core::{
+    cell::Cell,
+    ptr::{null_mut, read_volatile, write_volatile},
+    sync::atomic::{AtomicU64, Ordering},
+    usize,
+};
 
 use acpi::platform::Apic;
 use apic::{io_apic::IoApicBase, registers::TimerDivideConfigurationValue, ApicBase};
-use x86_64::{registers::model_specific::Msr, PhysAddr};
+use x86_64::{registers::model_specific::Msr, structures::idt::InterruptStackFrame, PhysAddr};
 
-use crate::arch::globals;
+use super::InterruptIndex;
+use crate::arch::{globals, time};
+
+/// Offset of the current count register of the LAPIC timer.
+const TIMER_CURRENT_COUNT_OFFSET: u64 = 0x390;
+
+/// Offsets of the low and high halves of the interrupt command register.
+const ICR_LOW_OFFSET: u64 = 0x300;
+const ICR_HIGH_OFFSET: u64 = 0x310;
+
+/// Bits of the low half of the interrupt command register.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Virtual address of the LAPIC registers. This is the same for every core and each core
+/// accesses its own LAPIC through it.
+static LAPIC_MEM: AtomicU64 = AtomicU64::new(0);
 
 /// Local APIC data.
 #[thread_local]
@@ -24,6 +45,7 @@ pub fn initialize_lapic() {
     let lapic_mem = lapic_mem as *mut ();
 
     let mut lapic_instance = unsafe { ApicBase::new(lapic_mem) };
+    LAPIC_MEM.store(lapic_mem as u64, Ordering::SeqCst);
 
     //TODO: Make sure the TPR (Task Priority Register) is set (so it won't block/postpone lower priority IRQs)
     // Enable local apic
@@ -33,18 +55,34 @@ pub fn initialize_lapic() {
         val.enable_apic_software(true);
     }
 
+    // Calibrate the timer once. All the cores use the same values.
+    lapic_instance
+        .timer_divide_configuration()
+        .update(|t| t.set(TimerDivideConfigurationValue::Divide64));
+    if !time::is_calibrated() {
+        lapic_instance.timer_local_vector_table_entry().update(|t| {
+            t.set_vector(super::InterruptIndex::Timer.as_u8());
+            t.set_timer_mode(false);
+            t.set_mask(true);
+        });
+
+        let current_count = (lapic_mem as u64 + TIMER_CURRENT_COUNT_OFFSET) as *const u32;
+        time::calibrate(
+            || lapic_instance.timer_initial_count().update(|t| t.set(u32::MAX)),
+            || unsafe { read_volatile(current_count) },
+        );
+    }
+
     // Enable timer
     {
-        lapic_instance
-            .timer_divide_configuration()
-            .update(|t| t.set(TimerDivideConfigurationValue::Divide64));
         lapic_instance.timer_local_vector_table_entry().update(|t| {
             t.set_vector(super::InterruptIndex::Timer.as_u8());
             t.set_timer_mode(true);
             t.set_mask(false);
         });
+        let initial_count = time::lapic_ticks_per_ms() * globals::TIMER_INTERVAL_MS;
         lapic_instance.timer_initial_count().update(|t| {
-            t.set(123456);
+            t.set(initial_count);
         });
     }
 
@@ -55,6 +93,57 @@ pub fn initialize_lapic() {
     }
 }
 
+/// Inter-processor interrupts that the cores send to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipi {
+    /// Wake up a halted core so that it polls its scheduler again.
+    Wakeup,
+    /// Flush the TLB. See [`crate::arch::memory::tlb`].
+    TlbShootdown,
+}
+
+impl Ipi {
+    fn vector(self) -> u8 {
+        match self {
+            Ipi::Wakeup => InterruptIndex::Wakeup.as_u8(),
+            Ipi::TlbShootdown => InterruptIndex::TlbShootdown.as_u8(),
+        }
+    }
+}
+
+/// Send `ipi` to the core with the given [`PROCESSOR_ID`].
+pub fn send_ipi(processor_id: usize, ipi: Ipi) {
+    let lapic_mem = LAPIC_MEM.load(Ordering::SeqCst);
+    if lapic_mem == 0 {
+        return;
+    }
+
+    let icr_low = (lapic_mem + ICR_LOW_OFFSET) as *mut u32;
+    let icr_high = (lapic_mem + ICR_HIGH_OFFSET) as *mut u32;
+
+    // The two halves must be written together. So, an interrupt handler on this core must not
+    // send another IPI in between.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        while read_volatile(icr_low) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+
+        // Fixed delivery to a physical destination. Writing the low half sends the IPI.
+        write_volatile(icr_high, (processor_id as u32) << 24);
+        write_volatile(icr_low, ICR_LEVEL_ASSERT | ipi.vector() as u32);
+    });
+}
+
+/// Handler for [`Ipi::Wakeup`]. The interrupt itself wakes the core up from `hlt`. So, there
+/// is nothing else to do.
+pub extern "x86-interrupt" fn wakeup_handler(_stack_frame: &mut InterruptStackFrame) {
+    unsafe {
+        let lapic = &mut LAPIC;
+        let eoi = lapic.end_of_interrupt();
+        eoi.signal();
+    }
+}
+
 /// Startup the IOApic. This is usually run on only one of the processor because IOApic is
 /// shared among multiple cores.
 pub fn initialize_ioapic(apic: Apic) {
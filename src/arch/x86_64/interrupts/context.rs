@@ -0,0 +1,97 @@
+//! Support for interrupt handlers that need the complete register state of the
+//! interrupted code. This is used when a user thread has to be stopped and resumed later.
+
+/// The state of the interrupted code as found on the stack by handlers generated with
+/// [`context_saving_handler`]. The general purpose registers are pushed by the handler and
+/// the rest is the frame pushed by the CPU.
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptedContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    /// Error code of the exception. This is zero for interrupts without an error code.
+    pub error_code: u64,
+
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl InterruptedContext {
+    /// Returns true if the interrupt was raised when running in ring 3.
+    pub fn is_user_mode(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+/// Defines a naked interrupt handler `$name` that stores all the registers of the interrupted
+/// code as an [`InterruptedContext`] and calls `$inner` with it.
+/// `$error_code` must be `"push 0"` for interrupts where the CPU doesn't push an error code so that
+/// the layout is the same for all handlers.
+macro_rules! context_saving_handler {
+    ($name:ident, $inner:path, $error_code:literal) => {
+        #[inline(never)]
+        #[naked]
+        pub unsafe extern "C" fn $name() {
+            unsafe {
+                asm!(concat!($error_code, "
+                push rax
+                push rbx
+                push rcx
+                push rdx
+                push rsi
+                push rdi
+                push rbp
+                push r8
+                push r9
+                push r10
+                push r11
+                push r12
+                push r13
+                push r14
+                push r15
+
+                cld
+                mov rdi, rsp
+                sub rsp, 8
+                call {0}
+                add rsp, 8
+
+                pop r15
+                pop r14
+                pop r13
+                pop r12
+                pop r11
+                pop r10
+                pop r9
+                pop r8
+                pop rbp
+                pop rdi
+                pop rsi
+                pop rdx
+                pop rcx
+                pop rbx
+                pop rax
+                add rsp, 8
+                iretq
+                "), sym $inner, options(noreturn));
+            }
+        }
+    };
+}
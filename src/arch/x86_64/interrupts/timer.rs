@@ -1,10 +1,19 @@
-use x86_64::structures::idt::InterruptStackFrame;
+//! LAPIC timer support. The timer is also used to preempt user threads.
 
-/// Handler than be used for timer
-pub extern "x86-interrupt" fn timer_handler(_stack_frame: &mut InterruptStackFrame) {
+use super::context::InterruptedContext;
+
+context_saving_handler!(timer_handler, timer_handler_inner, "push 0");
+
+extern "C" fn timer_handler_inner(context: &mut InterruptedContext) {
     unsafe {
         let lapic = &mut crate::arch::cpu_locals::LAPIC;
         let eoi = lapic.end_of_interrupt();
         eoi.signal();
     }
+
+    // Kernel code is cooperative. Only user threads are preempted and this call
+    // doesn't return in that case.
+    if context.is_user_mode() {
+        crate::arch::process::user_future::preempt_user_thread(context);
+    }
 }
@@ -1,10 +1,12 @@
 use core::{
-    alloc::{GlobalAlloc, Layout, LayoutError},
+    alloc::{GlobalAlloc, Layout},
     ptr,
 };
 
+use alloc::collections::BTreeMap;
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
@@ -22,13 +24,74 @@ pub fn get_frame_allocator_zeroed() -> impl FrameAllocator<Size4KiB> {
     PhysicalMemoryAllocatorWrapper { zeroed: true }
 }
 
+/// Allocate a zeroed frame of size `S`. This is used for huge pages.
+pub fn allocate_sized_frame_zeroed<S: PageSize>() -> Option<PhysFrame<S>> {
+    PhysicalMemoryAllocatorWrapper { zeroed: true }.allocate_frame()
+}
+
+/// Free a frame of size `S`. Huge frames are never shared. So, they are freed right away.
+///
+/// # Safety
+/// The frame must not be in use anymore.
+pub unsafe fn deallocate_sized_frame<S: PageSize>(frame: PhysFrame<S>) {
+    unsafe { PhysicalMemoryAllocatorWrapper { zeroed: false }.deallocate_frame(frame) };
+}
+
+/// Error returned when there are no free frames left.
+pub const ALLOCATION_FAILED: &str = "Cannot allocate frame";
+
+/// Number of bytes currently allocated from the physical memory allocator.
+#[cfg(feature = "self-checks")]
+pub fn allocated_bytes() -> usize {
+    PHYSICAL_MEMORY_ALLOCATOR.lock().stats_alloc_actual()
+}
+
+/// Number of bytes that can still be allocated from the physical memory allocator.
+pub fn free_bytes() -> usize {
+    let allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+    allocator.stats_total_bytes() - allocator.stats_alloc_actual()
+}
+
+/// Reference counts of frames that are shared between page tables, keyed by the
+/// physical address. Frames that are not in the map have a single owner.
+static FRAME_REFERENCES: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+/// Add a reference to a frame that is being shared. Each reference is released
+/// by deallocating the frame once.
+pub fn add_frame_reference(frame: PhysFrame<Size4KiB>) {
+    let mut references = FRAME_REFERENCES.lock();
+    *references.entry(frame.start_address().as_u64()).or_insert(1) += 1;
+}
+
+/// Get the number of owners of a frame.
+pub fn frame_reference_count(frame: PhysFrame<Size4KiB>) -> usize {
+    let references = FRAME_REFERENCES.lock();
+    *references.get(&frame.start_address().as_u64()).unwrap_or(&1)
+}
+
+/// Release a reference to the frame. Returns true if this was the last reference.
+fn release_frame_reference(frame: PhysFrame<Size4KiB>) -> bool {
+    let mut references = FRAME_REFERENCES.lock();
+    let addr = frame.start_address().as_u64();
+    match references.get_mut(&addr) {
+        None => true,
+        Some(count) => {
+            *count -= 1;
+            if *count == 1 {
+                references.remove(&addr);
+            }
+            false
+        }
+    }
+}
+
 struct PhysicalMemoryAllocatorWrapper {
     zeroed: bool,
 }
 
-unsafe impl FrameAllocator<Size4KiB> for PhysicalMemoryAllocatorWrapper {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let layout = Layout::from_size_align(4096, 4096).unwrap();
+unsafe impl<S: PageSize> FrameAllocator<S> for PhysicalMemoryAllocatorWrapper {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let layout = Layout::from_size_align(S::SIZE as usize, S::SIZE as usize).unwrap();
         let mem;
         if self.zeroed {
             mem = unsafe { PHYSICAL_MEMORY_ALLOCATOR.alloc_zeroed(layout) };
@@ -45,15 +108,20 @@ unsafe impl FrameAllocator<Size4KiB> for PhysicalMemoryAllocatorWrapper {
     }
 }
 
-impl FrameDeallocator<Size4KiB> for PhysicalMemoryAllocatorWrapper {
-    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        const LAYOUT: Result<Layout, LayoutError> = Layout::from_size_align(4096, 4096);
-
+impl<S: PageSize> FrameDeallocator<S> for PhysicalMemoryAllocatorWrapper {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        // Shared frames are only freed when the last owner releases them.
         let phys_addr = frame.start_address();
+        let small_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+        if S::SIZE == Size4KiB::SIZE && !release_frame_reference(small_frame) {
+            return;
+        }
+
+        let layout = Layout::from_size_align(S::SIZE as usize, S::SIZE as usize).unwrap();
         let virt_addr = phys_addr.as_u64() + globals::MEM_MAP_OFFSET_LOCATION;
 
         unsafe {
-            PHYSICAL_MEMORY_ALLOCATOR.dealloc(virt_addr as *mut u8, LAYOUT.unwrap());
+            PHYSICAL_MEMORY_ALLOCATOR.dealloc(virt_addr as *mut u8, layout);
         }
     }
 }
@@ -1,28 +1,88 @@
-use core::ops::Bound;
-
-use alloc::boxed::Box;
-use moondust_utils::{
-    id_generator::IdGenerator,
-    interval_tree::{Interval, IntervalTree},
+use core::{
+    cmp::min,
+    mem::size_of,
+    ops::Bound,
+    ptr, slice,
+    sync::atomic::{AtomicU32, Ordering},
 };
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use moondust_utils::interval_tree::{Interval, IntervalTree};
 use x86_64::{
-    registers::control::Cr3,
+    structures::idt::PageFaultErrorCode,
     structures::paging::{
-        page::PageRange, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
-        PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
+use super::{
+    frame_allocator, pcid,
+    tlb::{self, CpuSet},
+};
 use crate::{
-    arch::globals,
+    arch::{cpu_locals, globals},
     common::{
-        align_up,
-        memory::paging::{IMemoryMapper, MapperPermissions},
+        align_down,
+        memory::paging::{IMemoryMapper, MapperHints, MapperPermissions},
+        process::futex::Futex,
     },
 };
 
-static PROCESS_ID_GENERATOR: IdGenerator = IdGenerator::new();
+/// Marks read only entries of pages that are shared copy-on-write with another page table.
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Error of [`KernelPageTable::walk_to_p1_entry`] when there is no table for the address.
+const PAGE_NOT_MAPPED: &str = "Page is not mapped";
+
+/// Error for a size of user memory that is 0 or too large to fit the address space.
+pub const INVALID_SIZE: &str = "Invalid size";
+
+/// A region of user memory that is mapped on demand.
+#[derive(Debug, Clone, Copy)]
+struct ReservedArea {
+    /// End of the area (exclusive).
+    end: u64,
+    permissions: MapperPermissions,
+    kind: AreaKind,
+}
+
+/// The usage of a reserved area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AreaKind {
+    /// General purpose memory like the heap.
+    Anonymous,
+    /// Anonymous memory requested by the user. These can be unmapped and protected by the user.
+    Mapping,
+    /// Stack of the given thread.
+    Stack(usize),
+    /// Guard below the stack of the given thread. This is never mapped.
+    StackGuard(usize),
+}
+
+/// Reason for a user page fault that could not be resolved.
+#[derive(Debug, Clone, Copy)]
+pub enum PageFaultError {
+    /// The fault is in the guard area of the stack of the given thread.
+    StackOverflow(usize),
+    /// The access is not valid.
+    Invalid(&'static str),
+    /// There is no free memory to resolve the fault.
+    OutOfMemory,
+}
+
+impl From<&'static str> for PageFaultError {
+    fn from(error: &'static str) -> Self {
+        if error == frame_allocator::ALLOCATION_FAILED {
+            PageFaultError::OutOfMemory
+        } else {
+            PageFaultError::Invalid(error)
+        }
+    }
+}
 
 /// Structure for a processes main address space.
 #[derive(Debug)]
@@ -30,6 +90,19 @@ pub struct KernelPageTable {
     page_table: Box<PageTable>,
     vmem_allocated: usize,
     mem_areas: IntervalTree<u64>,
+    /// Areas in `mem_areas` that are only reserved. These are mapped a page at a time
+    /// when the user first accesses them. Keyed by the start address.
+    reserved_areas: BTreeMap<u64, ReservedArea>,
+    /// Futexes with waiting threads keyed by the user address of the futex word.
+    futexes: BTreeMap<u64, Arc<Futex>>,
+    /// Cores that have this page table active. These get the TLB shootdowns.
+    active_cpus: Arc<CpuSet>,
+    /// Unique id of this page table. See [`pcid`].
+    table_id: u64,
+    pcid: u16,
+    /// Incremented on every TLB shootdown. Cores that had this page table active before
+    /// missed the shootdowns and must flush the entries of the PCID when they switch back.
+    tlb_generation: u64,
     process_id: usize,
 
     heap_allocated: usize,
@@ -39,15 +112,21 @@ pub struct KernelPageTable {
 }
 
 impl KernelPageTable {
-    /// Create an empty KernelPageTable.
-    pub fn new(page_table: Box<PageTable>) -> Self {
+    /// Create an empty KernelPageTable for the process with `process_id`.
+    pub fn new(page_table: Box<PageTable>, process_id: usize) -> Self {
         let val = Self {
             page_table,
             vmem_allocated: 0,
             mem_areas: IntervalTree::new(),
+            reserved_areas: BTreeMap::new(),
+            futexes: BTreeMap::new(),
+            active_cpus: Arc::new(CpuSet::new()),
+            table_id: pcid::new_table_id(),
+            pcid: pcid::pcid_for_process(process_id),
+            tlb_generation: 0,
             heap_allocated: 0,
             user_stack_allocated_until: globals::USER_STACK_END,
-            process_id: PROCESS_ID_GENERATOR.get_value(),
+            process_id,
         };
 
         info!(target: "kernel_page_table", "Created a page table with id {}", val.process_id);
@@ -56,10 +135,20 @@ impl KernelPageTable {
 
     /// Get the mapper that can map/unmap vmem.
     fn get_mapper(&mut self) -> impl IMemoryMapper + '_ {
+        self.offset_page_table()
+    }
+
+    fn offset_page_table(&mut self) -> OffsetPageTable<'_> {
         let offset = VirtAddr::new(globals::MEM_MAP_OFFSET_LOCATION);
         unsafe { OffsetPageTable::new(&mut self.page_table, offset) }
     }
 
+    /// Flush `pages` pages from `start` on all the cores that have this page table active.
+    fn shootdown(&mut self, start: VirtAddr, pages: u64) {
+        self.tlb_generation += 1;
+        tlb::shootdown(&self.active_cpus, start, pages);
+    }
+
     /// Activate the page table.
     pub fn activate(&mut self) {
         let pt_vaddr = self.page_table.as_ref() as *const PageTable as *const ();
@@ -68,10 +157,22 @@ impl KernelPageTable {
             .virt_to_phys(pt_vaddr)
             .expect("Cannot find phys mapping");
         let frame = PhysFrame::from_start_address(PhysAddr::new(phys as u64)).unwrap();
-        let (_, flags) = Cr3::read();
+
+        // The core is added before the switch so that no shootdown is missed.
+        self.active_cpus.insert(cpu_locals::PROCESSOR_ID.get());
         unsafe {
-            Cr3::write(frame, flags);
+            pcid::switch(frame, self.pcid, self.table_id, self.tlb_generation);
         }
+        tlb::set_active_cpus(Some(self.active_cpus.clone()));
+    }
+
+    pub fn process_id(&self) -> usize {
+        self.process_id
+    }
+
+    /// Get the amount of user memory mapped or reserved in this page table.
+    pub fn vmem_allocated(&self) -> usize {
+        self.vmem_allocated
     }
 
     /// Get the amount of heap currently allocated.
@@ -84,30 +185,635 @@ impl KernelPageTable {
         &mut self,
         size_to_increase: usize,
     ) -> Result<(usize, usize), &'static str> {
-        let size_to_increase = align_up(size_to_increase, globals::PAGE_SIZE);
-        let final_size = self.heap_allocated + size_to_increase;
+        let address_to_allocate_from = globals::USER_HEAP_START + self.heap_allocated;
+        if size_to_increase == 0 {
+            return Ok((address_to_allocate_from, address_to_allocate_from));
+        }
+
+        let size_to_increase = page_aligned_size(size_to_increase)?;
+        let final_size = self
+            .heap_allocated
+            .checked_add(size_to_increase)
+            .ok_or(INVALID_SIZE)?;
 
         // Max heap size reached.
         if final_size >= globals::USER_HEAP_END - globals::USER_HEAP_START {
             return Err("Max heap size reached");
         }
 
-        let address_to_allocate_from = globals::USER_HEAP_START + self.heap_allocated;
-        let val = self.map_with_alloc(
+        // The heap is only mapped when it is used. Refuse to grow it if the memory is already
+        // gone so that the user sees the failure instead of a fault later.
+        if size_to_increase > frame_allocator::free_bytes() {
+            return Err(frame_allocator::ALLOCATION_FAILED);
+        }
+
+        self.reserve(
             address_to_allocate_from as _,
             size_to_increase,
             MapperPermissions::WRITE | MapperPermissions::RING_3 | MapperPermissions::READ,
-        );
-        if val.is_err() {
-            // TODO: kill this process?
-            return Err("Out of memory while allocating heap");
-        }
+        )?;
         self.heap_allocated = final_size;
         Ok((
             address_to_allocate_from,
             address_to_allocate_from + size_to_increase,
         ))
     }
+
+    /// Reserve a region of user memory without allocating any frames. The pages are mapped
+    /// with the given permissions when they are first accessed. See [`Self::handle_page_fault`].
+    pub fn reserve(
+        &mut self,
+        virt_addr: *const u8,
+        size: usize,
+        permissions: MapperPermissions,
+    ) -> Result<(), &'static str> {
+        self.reserve_area(virt_addr as u64, size, permissions, AreaKind::Anonymous)
+    }
+
+    /// Reserve a stack of `size` bytes for the given thread ending at `stack_end` (exclusive).
+    /// A guard area of [`globals::USER_STACK_GUARD_SIZE`] is reserved below it so that an
+    /// overflow is reported instead of running into the next stack.
+    pub fn reserve_stack(
+        &mut self,
+        stack_end: u64,
+        size: usize,
+        thread_id: usize,
+    ) -> Result<(), &'static str> {
+        let stack_start = stack_end - size as u64;
+        let guard_start = stack_start - globals::USER_STACK_GUARD_SIZE as u64;
+        self.reserve_area(
+            guard_start,
+            globals::USER_STACK_GUARD_SIZE,
+            MapperPermissions::READ,
+            AreaKind::StackGuard(thread_id),
+        )?;
+        self.reserve_area(
+            stack_start,
+            size,
+            MapperPermissions::READ | MapperPermissions::RING_3 | MapperPermissions::WRITE,
+            AreaKind::Stack(thread_id),
+        )
+    }
+
+    /// Reserve `size` bytes of anonymous memory for the user at `addr`. If `addr` is 0, the
+    /// memory is placed in the free space between [`globals::USER_MMAP_START`] and
+    /// [`globals::USER_MMAP_END`]. Returns the start of the memory.
+    pub fn map_anonymous(
+        &mut self,
+        addr: u64,
+        size: usize,
+        permissions: MapperPermissions,
+    ) -> Result<u64, &'static str> {
+        let size = page_aligned_size(size)?;
+
+        let start = if addr == 0 {
+            self.find_free_range(size)?
+        } else {
+            if addr % globals::PAGE_SIZE as u64 != 0 {
+                return Err("Address is not page aligned");
+            }
+            let end = addr.checked_add(size as u64).ok_or(INVALID_SIZE)?;
+            if !crate::arch::is_user_address(addr) || !crate::arch::is_user_address(end - 1) {
+                return Err("Range is not in user memory");
+            }
+            addr
+        };
+
+        let permissions = permissions | MapperPermissions::READ | MapperPermissions::RING_3;
+        self.reserve_area(start, size, permissions, AreaKind::Mapping)?;
+        Ok(start)
+    }
+
+    /// Remove the anonymous memory in `[addr, addr + size)`. The range must only cover memory
+    /// from [`Self::map_anonymous`]. Parts of a mapping can be removed.
+    pub fn unmap_anonymous(&mut self, addr: u64, size: usize) -> Result<(), &'static str> {
+        let end = Self::mapping_range_end(addr, size)?;
+        let areas = self.split_mapping_areas(addr, end)?;
+        for start in areas {
+            let area = self.reserved_areas.remove(&start).unwrap();
+            let interval = Interval::new(Bound::Included(start), Bound::Excluded(area.end));
+            self.mem_areas = self.mem_areas.remove(&interval);
+        }
+
+        // This also frees the frames and updates the allocated memory.
+        self.unmap_range(addr as *const u8, (end - addr) as usize)
+    }
+
+    /// Change the permissions of the anonymous memory in `[addr, addr + size)`. The range must
+    /// only cover memory from [`Self::map_anonymous`].
+    pub fn protect_anonymous(
+        &mut self,
+        addr: u64,
+        size: usize,
+        permissions: MapperPermissions,
+    ) -> Result<(), &'static str> {
+        let end = Self::mapping_range_end(addr, size)?;
+        let permissions = permissions | MapperPermissions::READ | MapperPermissions::RING_3;
+        let areas = self.split_mapping_areas(addr, end)?;
+        for start in areas {
+            self.reserved_areas.get_mut(&start).unwrap().permissions = permissions;
+        }
+
+        // Update the pages that are already mapped. Shared pages must stay copy-on-write.
+        for page in (addr..end).step_by(globals::PAGE_SIZE) {
+            let entry = match self.walk_to_p1_entry(VirtAddr::new(page), false) {
+                Ok(entry) if !entry.is_unused() => entry,
+                Ok(_) | Err(PAGE_NOT_MAPPED) => continue,
+                Err(error) => return Err(error),
+            };
+
+            let frame = entry.frame().map_err(|_| "Invalid frame in page table")?;
+            let mut flags = page_table_flags(permissions);
+            if flags.contains(PageTableFlags::WRITABLE)
+                && frame_allocator::frame_reference_count(frame) > 1
+            {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+            }
+            entry.set_flags(flags);
+        }
+
+        self.shootdown(VirtAddr::new(addr), (end - addr) / globals::PAGE_SIZE as u64);
+        Ok(())
+    }
+
+    /// Validate a range given to [`Self::unmap_anonymous`] or [`Self::protect_anonymous`] and
+    /// get its end.
+    fn mapping_range_end(addr: u64, size: usize) -> Result<u64, &'static str> {
+        if addr % globals::PAGE_SIZE as u64 != 0 || size % globals::PAGE_SIZE != 0 {
+            return Err("Range is not page aligned");
+        }
+        if size == 0 {
+            return Err("Size cannot be 0");
+        }
+        addr.checked_add(size as u64).ok_or("Range is too large")
+    }
+
+    /// Find a free range of `size` bytes for a mapping.
+    fn find_free_range(&self, size: usize) -> Result<u64, &'static str> {
+        let mut start = globals::USER_MMAP_START as u64;
+        loop {
+            let end = start.checked_add(size as u64).ok_or(INVALID_SIZE)?;
+            if end > globals::USER_MMAP_END as u64 + 1 {
+                return Err("No free space for the mapping");
+            }
+
+            // Skip past the areas in the way.
+            let interval = Interval::new(Bound::Included(start), Bound::Excluded(end));
+            let next = self
+                .mem_areas
+                .query_interval(&interval)
+                .map(|interval| Self::interval_pages(&interval).1)
+                .max();
+            match next {
+                Some(next) => start = next,
+                None => return Ok(start),
+            }
+        }
+    }
+
+    /// Split the mappings so that `[start, end)` is covered by whole areas. Returns the starts
+    /// of those areas. Nothing is changed if part of the range is not covered by mappings.
+    fn split_mapping_areas(&mut self, start: u64, end: u64) -> Result<Vec<u64>, &'static str> {
+        let mut current = start;
+        while current < end {
+            match self.reserved_areas.range(..=current).next_back() {
+                Some((_, area)) if current < area.end && area.kind == AreaKind::Mapping => {
+                    current = area.end;
+                }
+                _ => return Err("Range is not covered by mappings"),
+            }
+        }
+
+        self.split_area_at(start);
+        self.split_area_at(end);
+        Ok(self.reserved_areas.range(start..end).map(|(start, _)| *start).collect())
+    }
+
+    /// Split the area that contains `addr` into two areas at `addr`.
+    fn split_area_at(&mut self, addr: u64) {
+        let (start, area) = match self.reserved_areas.range(..addr).next_back() {
+            Some((start, area)) if addr < area.end => (*start, *area),
+            _ => return,
+        };
+
+        let whole = Interval::new(Bound::Included(start), Bound::Excluded(area.end));
+        let low = Interval::new(Bound::Included(start), Bound::Excluded(addr));
+        let high = Interval::new(Bound::Included(addr), Bound::Excluded(area.end));
+        self.mem_areas = self.mem_areas.remove(&whole).insert(low).insert(high);
+        self.reserved_areas.insert(start, ReservedArea { end: addr, ..area });
+        self.reserved_areas.insert(addr, area);
+    }
+
+    fn reserve_area(
+        &mut self,
+        start: u64,
+        size: usize,
+        permissions: MapperPermissions,
+        kind: AreaKind,
+    ) -> Result<(), &'static str> {
+        debug_assert!(size % globals::PAGE_SIZE == 0, "Size must be page aligned");
+        if crate::arch::is_kernel_mode(start) {
+            return Err("Only user memory can be reserved");
+        }
+
+        let end = start + size as u64;
+        let interval = Interval::new(Bound::Included(start), Bound::Excluded(end));
+        if self.mem_areas.query_interval(&interval).next().is_some() {
+            return Err("Region overlaps an existing area");
+        }
+
+        if !matches!(kind, AreaKind::StackGuard(_)) {
+            self.vmem_allocated += size;
+        }
+        self.mem_areas = self.mem_areas.insert(interval);
+        let area = ReservedArea {
+            end,
+            permissions,
+            kind,
+        };
+        self.reserved_areas.insert(start, area);
+        Ok(())
+    }
+
+    /// Try to resolve a page fault caused by the user at `addr`. Faults in reserved areas are
+    /// resolved by mapping a zeroed frame. This is how stacks grow down towards their guard.
+    /// An error is returned if the fault cannot be resolved.
+    pub fn handle_page_fault(
+        &mut self,
+        addr: u64,
+        error_code: PageFaultErrorCode,
+    ) -> Result<(), PageFaultError> {
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            let addr = VirtAddr::new(addr);
+            if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && self.is_copy_on_write(addr)
+            {
+                return self.break_copy_on_write(addr).map_err(PageFaultError::from);
+            }
+            return Err(PageFaultError::Invalid("Access violates page permissions"));
+        }
+
+        let area = match self.reserved_areas.range(..=addr).next_back() {
+            Some((_, area)) if addr < area.end => *area,
+            _ => return Err(PageFaultError::Invalid("Address is not in a reserved area")),
+        };
+
+        if let AreaKind::StackGuard(thread_id) = area.kind {
+            return Err(PageFaultError::StackOverflow(thread_id));
+        }
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !area.permissions.contains(MapperPermissions::WRITE)
+        {
+            return Err(PageFaultError::Invalid("Write to a read only area"));
+        }
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && !area.permissions.contains(MapperPermissions::EXECUTE)
+        {
+            return Err(PageFaultError::Invalid("Instruction fetch from a non executable area"));
+        }
+
+        // Another thread of this process might have faulted on the same page already.
+        let page_start = align_down(addr as usize, globals::PAGE_SIZE);
+        if self.virt_to_phys(page_start as _).is_some() {
+            return Ok(());
+        }
+
+        self.get_mapper()
+            .map_with_alloc(
+                page_start as _,
+                globals::PAGE_SIZE,
+                area.permissions,
+                MapperHints::empty(),
+            )
+            .map_err(PageFaultError::from)
+    }
+
+    /// Create a copy of the user address space. All the mapped user pages are shared with
+    /// the copy and the writable ones are marked copy-on-write in both the page tables.
+    /// `page_table` is the level 4 table for the copy with the kernel entries set up and
+    /// `process_id` is the process of the copy.
+    pub fn fork(
+        &mut self,
+        page_table: Box<PageTable>,
+        process_id: usize,
+    ) -> Result<KernelPageTable, &'static str> {
+        let mut child = KernelPageTable::new(page_table, process_id);
+        child.vmem_allocated = self.vmem_allocated;
+        child.mem_areas = self.mem_areas.clone();
+        child.reserved_areas = self.reserved_areas.clone();
+        child.heap_allocated = self.heap_allocated;
+        child.user_stack_allocated_until = self.user_stack_allocated_until;
+
+        let mut protected = false;
+        let areas = self.mem_areas.clone();
+        for interval in areas.iter() {
+            let (start, end) = Self::interval_pages(&interval);
+            for addr in (start..end).step_by(globals::PAGE_SIZE) {
+                let addr = VirtAddr::new(addr);
+                // Splitting a huge page on the way can run out of memory.
+                let entry = match self.walk_to_p1_entry(addr, false) {
+                    Ok(entry) if !entry.is_unused() => entry,
+                    Ok(_) | Err(PAGE_NOT_MAPPED) => continue,
+                    Err(error) => return Err(error),
+                };
+
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(COPY_ON_WRITE);
+                    entry.set_flags(flags);
+                    protected = true;
+                }
+
+                // The reference is only added once the child has the entry. The child releases
+                // the references of its entries when it is dropped on an error.
+                let frame = entry.frame().map_err(|_| "Invalid frame in page table")?;
+                child.walk_to_p1_entry(addr, true)?.set_frame(frame, flags);
+                frame_allocator::add_frame_reference(frame);
+            }
+        }
+
+        // Other threads of this process must not write to the shared frames anymore.
+        if protected {
+            self.shootdown(VirtAddr::zero(), u64::MAX);
+        }
+
+        info!(
+            target: "kernel_page_table",
+            "Forked page table {} into {}", self.process_id, child.process_id
+        );
+        Ok(child)
+    }
+
+    /// Check that `[addr, addr + size)` only covers user memory that belongs to this address
+    /// space. The pages in the range might not be mapped yet.
+    pub fn is_valid_user_range(&self, addr: u64, size: usize) -> bool {
+        let end = match addr.checked_add(size as u64) {
+            Some(end) => end,
+            None => return false,
+        };
+        if size == 0 {
+            return true;
+        }
+        if crate::arch::is_kernel_mode(addr) || crate::arch::is_kernel_mode(end - 1) {
+            return false;
+        }
+
+        // Areas can be adjacent to each other. So, walk through the areas that cover the range.
+        let mut current = addr;
+        while current < end {
+            let area_end = self
+                .mem_areas
+                .query_point(&current)
+                .map(|interval| match interval.high() {
+                    Bound::Included(a) => *a + 1,
+                    Bound::Excluded(a) => *a,
+                    Bound::Unbounded => panic!("Cannot have unbounded interval!"),
+                })
+                .max();
+
+            match area_end {
+                Some(area_end) => current = area_end,
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Write `value` into the user memory at `addr`. See [`Self::copy_to_user`].
+    pub fn write_to_user<T>(&mut self, addr: u64, value: &T) -> Result<(), &'static str> {
+        let data = unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        self.copy_to_user(addr, data)
+    }
+
+    /// Copy `data` into the user memory at `addr` of this address space. This doesn't need the
+    /// page table to be active. Pages that are not mapped yet or are copy-on-write are resolved
+    /// first, as if the user wrote to them. All the pages are resolved before anything is
+    /// written. So, nothing is written if part of the range is not accessible.
+    pub fn copy_to_user(&mut self, addr: u64, data: &[u8]) -> Result<(), &'static str> {
+        if !self.is_valid_user_range(addr, data.len()) {
+            return Err("Invalid user address range");
+        }
+
+        // The pages stay where they are while the page table is borrowed. Resolving one page
+        // only changes the entry of that page.
+        let mut chunks = Vec::new();
+        let mut resolved = 0;
+        while resolved < data.len() {
+            let current = addr + resolved as u64;
+            let length = min(
+                globals::PAGE_SIZE - (current as usize % globals::PAGE_SIZE),
+                data.len() - resolved,
+            );
+
+            let target = self.prepare_user_page(VirtAddr::new(current), true)?;
+            chunks.push((target, length));
+            resolved += length;
+        }
+
+        let mut copied = 0;
+        for (target, length) in chunks {
+            unsafe {
+                ptr::copy_nonoverlapping(data[copied..].as_ptr(), target as *mut u8, length);
+            }
+            copied += length;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the user memory at `addr` of this address space into `data`. Pages that are not
+    /// mapped yet are resolved first, as if the user read from them.
+    pub fn copy_from_user(&mut self, addr: u64, data: &mut [u8]) -> Result<(), &'static str> {
+        if !self.is_valid_user_range(addr, data.len()) {
+            return Err("Invalid user address range");
+        }
+
+        let mut copied = 0;
+        while copied < data.len() {
+            let current = addr + copied as u64;
+            let length = min(
+                globals::PAGE_SIZE - (current as usize % globals::PAGE_SIZE),
+                data.len() - copied,
+            );
+
+            let source = self.prepare_user_page(VirtAddr::new(current), false)?;
+            unsafe {
+                ptr::copy_nonoverlapping(source as *const u8, data[copied..].as_mut_ptr(), length);
+            }
+            copied += length;
+        }
+
+        Ok(())
+    }
+
+    /// Get the futex for the 32 bit word at the user address `addr` along with the current
+    /// value of the word. The futex is identified by the returned key which is the user
+    /// address of the word. The physical address is not stable: a fork makes the page
+    /// copy-on-write and the next write moves the word of this address space to a new frame.
+    /// The page is made writable first so that the value is read from that frame.
+    pub fn get_futex(&mut self, addr: u64) -> Result<(u64, Arc<Futex>, u32), &'static str> {
+        let size = size_of::<u32>();
+        if addr % size as u64 != 0 || !self.is_valid_user_range(addr, size) {
+            return Err("Invalid futex address");
+        }
+
+        let word = self.prepare_user_page(VirtAddr::new(addr), true)?;
+        let value = unsafe { (*(word as *const AtomicU32)).load(Ordering::SeqCst) };
+        let futex = self
+            .futexes
+            .entry(addr)
+            .or_insert_with(|| Arc::new(Futex::new()))
+            .clone();
+        Ok((addr, futex, value))
+    }
+
+    /// Forget the futex with the given key if no thread is waiting on it.
+    pub fn release_futex(&mut self, key: u64) {
+        if let Some(futex) = self.futexes.get(&key) {
+            if futex.waiters() == 0 {
+                self.futexes.remove(&key);
+            }
+        }
+    }
+
+    /// Make sure that the user page at `addr` is mapped and accessible by the user. If `write`
+    /// is set, the page is also made writable by this page table alone. Returns the address
+    /// of `addr` in the physical memory mapping.
+    fn prepare_user_page(&mut self, addr: VirtAddr, write: bool) -> Result<u64, &'static str> {
+        let flags = match self.walk_to_p1_entry(addr, false) {
+            Ok(entry) => entry.flags(),
+            Err(_) => PageTableFlags::empty(),
+        };
+
+        let mut error_code = PageFaultErrorCode::USER_MODE;
+        if write {
+            error_code |= PageFaultErrorCode::CAUSED_BY_WRITE;
+        }
+
+        let resolved = flags.contains(PageTableFlags::PRESENT)
+            && (!write || flags.contains(PageTableFlags::WRITABLE));
+        if !resolved {
+            if flags.contains(PageTableFlags::PRESENT) {
+                error_code |= PageFaultErrorCode::PROTECTION_VIOLATION;
+            }
+            self.handle_page_fault(addr.as_u64(), error_code)
+                .map_err(|_| "Address is not accessible")?;
+        }
+
+        let entry = self.walk_to_p1_entry(addr, false)?;
+        if !entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+            return Err("Address is not accessible");
+        }
+        let frame = entry.frame().map_err(|_| "Invalid frame in page table")?;
+        let offset = addr.as_u64() % globals::PAGE_SIZE as u64;
+        Ok(frame.start_address().as_u64() + offset + globals::MEM_MAP_OFFSET_LOCATION)
+    }
+
+    fn is_copy_on_write(&mut self, addr: VirtAddr) -> bool {
+        match self.walk_to_p1_entry(addr, false) {
+            Ok(entry) => entry.flags().contains(COPY_ON_WRITE),
+            Err(_) => false,
+        }
+    }
+
+    /// Give this page table its own writable copy of a copy-on-write page. The frame is
+    /// reused if no one else refers to it anymore.
+    fn break_copy_on_write(&mut self, addr: VirtAddr) -> Result<(), &'static str> {
+        let entry = self.walk_to_p1_entry(addr, false)?;
+        let frame = entry.frame().map_err(|_| "Invalid frame in page table")?;
+        let mut flags = entry.flags();
+        flags.remove(COPY_ON_WRITE);
+        flags.insert(PageTableFlags::WRITABLE);
+
+        if frame_allocator::frame_reference_count(frame) > 1 {
+            let new_frame = frame_allocator::get_frame_allocator()
+                .allocate_frame()
+                .ok_or(frame_allocator::ALLOCATION_FAILED)?;
+            let source = frame.start_address().as_u64() + globals::MEM_MAP_OFFSET_LOCATION;
+            let target = new_frame.start_address().as_u64() + globals::MEM_MAP_OFFSET_LOCATION;
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    source as *const u8,
+                    target as *mut u8,
+                    globals::PAGE_SIZE,
+                );
+            }
+            entry.set_frame(new_frame, flags);
+            self.shootdown(addr, 1);
+
+            // Release this page table's reference to the shared frame. This is only done after
+            // no core can access it through this page table anymore.
+            unsafe { frame_allocator::get_frame_deallocator().deallocate_frame(frame) };
+        } else {
+            entry.set_flags(flags);
+            // Other cores would fault on their stale read only entry otherwise.
+            self.shootdown(addr, 1);
+        }
+
+        Ok(())
+    }
+
+    /// Walk the user half of the page table and get the level 1 entry for `addr`.
+    /// If `create` is set, missing tables are allocated on the way.
+    fn walk_to_p1_entry(
+        &mut self,
+        addr: VirtAddr,
+        create: bool,
+    ) -> Result<&mut PageTableEntry, &'static str> {
+        let mut table: &mut PageTable = &mut self.page_table;
+        let levels = [(4, addr.p4_index()), (3, addr.p3_index()), (2, addr.p2_index())];
+        for (level, index) in levels.iter() {
+            let entry = &mut table[*index];
+            if entry.is_unused() {
+                if !create {
+                    return Err(PAGE_NOT_MAPPED);
+                }
+
+                let frame = frame_allocator::get_frame_allocator_zeroed()
+                    .allocate_frame()
+                    .ok_or(frame_allocator::ALLOCATION_FAILED)?;
+                entry.set_frame(
+                    frame,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | PageTableFlags::USER_ACCESSIBLE,
+                );
+            } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                // The callers change single pages. So, huge pages are split.
+                split_huge_entry(entry, *level)?;
+            }
+
+            let next = entry.addr().as_u64() + globals::MEM_MAP_OFFSET_LOCATION;
+            table = unsafe { &mut *(next as *mut PageTable) };
+        }
+
+        Ok(&mut table[addr.p1_index()])
+    }
+
+    /// Get the page aligned range `[start, end)` covered by an interval in `mem_areas`.
+    fn interval_pages(interval: &Interval<u64>) -> (u64, u64) {
+        let start_val = match interval.low() {
+            Bound::Included(a) => *a,
+            Bound::Excluded(a) => *a + 1,
+            Bound::Unbounded => panic!("Cannot have unbounded interval!"),
+        };
+
+        let end_val = match interval.high() {
+            Bound::Included(a) => *a,
+            Bound::Excluded(a) => *a - 1,
+            Bound::Unbounded => panic!("Cannot have unbounded interval!"),
+        };
+
+        let start = Page::<Size4KiB>::containing_address(VirtAddr::new(start_val));
+        let end = Page::<Size4KiB>::containing_address(VirtAddr::new(end_val));
+        (
+            start.start_address().as_u64(),
+            end.start_address().as_u64() + globals::PAGE_SIZE as u64,
+        )
+    }
 }
 
 // we implement this wrapper in order to track the user regions
@@ -120,7 +826,12 @@ impl<'a> IMemoryMapper for KernelPageTable {
         virt_addr: *const u8,
         size: usize,
         permissions: MapperPermissions,
+        hints: MapperHints,
     ) -> Result<(), &'static str> {
+        // Partial mappings are rolled back on errors. So, nothing is tracked then.
+        self.get_mapper()
+            .map(phys_addr, virt_addr, size, permissions, hints)?;
+
         if !crate::arch::is_kernel_mode(virt_addr as u64) {
             self.vmem_allocated += size;
             self.mem_areas = self.mem_areas.insert(Interval::new(
@@ -128,9 +839,7 @@ impl<'a> IMemoryMapper for KernelPageTable {
                 Bound::Excluded(virt_addr as u64 + size as u64),
             ));
         }
-
-        self.get_mapper()
-            .map(phys_addr, virt_addr, size, permissions)
+        Ok(())
     }
 
     fn map_with_alloc(
@@ -138,7 +847,11 @@ impl<'a> IMemoryMapper for KernelPageTable {
         virt_addr: *const u8,
         size: usize,
         permissions: MapperPermissions,
+        hints: MapperHints,
     ) -> Result<(), &'static str> {
+        self.get_mapper()
+            .map_with_alloc(virt_addr, size, permissions, hints)?;
+
         if !crate::arch::is_kernel_mode(virt_addr as u64) {
             self.vmem_allocated += size;
             self.mem_areas = self.mem_areas.insert(Interval::new(
@@ -146,9 +859,7 @@ impl<'a> IMemoryMapper for KernelPageTable {
                 Bound::Excluded(virt_addr as u64 + size as u64),
             ));
         }
-
-        self.get_mapper()
-            .map_with_alloc(virt_addr, size, permissions)
+        Ok(())
     }
 
     fn unmap_range(&mut self, virt_addr: *const u8, size: usize) -> Result<(), &'static str> {
@@ -156,7 +867,19 @@ impl<'a> IMemoryMapper for KernelPageTable {
             self.vmem_allocated -= size;
         }
 
-        self.get_mapper().unmap_range(virt_addr, size)
+        let start = VirtAddr::from_ptr(virt_addr);
+        let mut frames = Vec::new();
+        unmap_pages(&mut self.offset_page_table(), start, size, |_, frame| {
+            frames.push(frame)
+        })?;
+
+        // The frames can only be reused once no core can access them anymore.
+        self.shootdown(start, (size / globals::PAGE_SIZE) as u64);
+        for frame in frames {
+            unsafe { deallocate_mapped_frame(frame) };
+        }
+
+        Ok(())
     }
 
     fn virt_to_phys(&mut self, virt_addr: *const ()) -> Option<*const ()> {
@@ -174,27 +897,43 @@ impl Drop for KernelPageTable {
 
         let areas = self.mem_areas.clone();
         for interval in areas.iter() {
-            let start_val = match interval.low() {
-                Bound::Included(a) => *a,
-                Bound::Excluded(a) => *a + 1,
-                Bound::Unbounded => panic!("Cannot have unbounded interval!"),
-            };
+            let (start, end) = Self::interval_pages(&interval);
+            self.get_mapper()
+                .unmap_range(start as *const u8, (end - start) as usize)
+                .unwrap();
+        }
 
-            let end_val = match interval.high() {
-                Bound::Included(a) => *a,
-                Bound::Excluded(a) => *a - 1,
-                Bound::Unbounded => panic!("Cannot have unbounded interval!"),
-            };
+        // The kernel half is shared with all the other page tables.
+        for entry in self.page_table.iter_mut().take(256) {
+            if !entry.is_unused() {
+                free_table(entry, 4);
+            }
+        }
+    }
+}
 
-            let start = Page::<Size4KiB>::containing_address(VirtAddr::new(start_val));
-            let end = Page::<Size4KiB>::containing_address(VirtAddr::new(end_val));
-            let size = end.start_address() - start.start_address() + 4096;
+/// Free the table that `entry` points to and all the tables below it. `level` is the level of
+/// the table that holds `entry`. The pages must be unmapped already.
+fn free_table(entry: &mut PageTableEntry, level: u8) {
+    if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        warn!(
+            target: "kernel_page_table",
+            "Frame {:#x} is still mapped during teardown", entry.addr().as_u64()
+        );
+        return;
+    }
 
-            self.get_mapper()
-                .unmap_range(start.start_address().as_ptr(), size as usize)
-                .unwrap();
+    let table_addr = entry.addr().as_u64() + globals::MEM_MAP_OFFSET_LOCATION;
+    let table = unsafe { &mut *(table_addr as *mut PageTable) };
+    for child in table.iter_mut() {
+        if !child.is_unused() {
+            free_table(child, level - 1);
         }
     }
+
+    let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+    unsafe { frame_allocator::get_frame_deallocator().deallocate_frame(frame) };
+    entry.set_unused();
 }
 
 impl<'a> IMemoryMapper for OffsetPageTable<'a> {
@@ -204,46 +943,12 @@ impl<'a> IMemoryMapper for OffsetPageTable<'a> {
         virt_addr: *const u8,
         size: usize,
         permissions: MapperPermissions,
+        hints: MapperHints,
     ) -> Result<(), &'static str> {
         debug_assert!(size % 4096 == 0, "Size must be page aligned");
 
-        let mut perms: PageTableFlags = PageTableFlags::PRESENT;
-        if !permissions.contains(MapperPermissions::EXECUTE) {
-            perms = perms | PageTableFlags::NO_EXECUTE;
-        }
-
-        if permissions.contains(MapperPermissions::WRITE) {
-            perms = perms | PageTableFlags::WRITABLE;
-        }
-
-        if permissions.contains(MapperPermissions::RING_3) {
-            perms = perms | PageTableFlags::USER_ACCESSIBLE;
-        }
-
-        let page_range: PageRange = {
-            let start_page = Page::<Size4KiB>::from_start_address(VirtAddr::from_ptr(virt_addr))
-                .expect("start addr is no aligned");
-            let end_page =
-                Page::<Size4KiB>::from_start_address(VirtAddr::new(virt_addr as u64 + size as u64))
-                    .unwrap();
-            Page::range(start_page, end_page)
-        };
-
-        let mut frame_allocator = super::frame_allocator::get_frame_allocator();
-        for (index, page) in page_range.into_iter().enumerate() {
-            let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(
-                phys_addr as u64 + (index as u64 * 4096),
-            ))
-            .expect("Physical Frame creation failed.");
-
-            unsafe {
-                self.map_to(page, frame, perms, &mut frame_allocator)
-                    .expect("Mapping failed.")
-                    .flush();
-            }
-        }
-
-        Ok(())
+        let flags = page_table_flags(permissions);
+        map_pages(self, virt_addr as u64, Some(phys_addr as u64), size, flags, hints)
     }
 
     fn map_with_alloc(
@@ -251,72 +956,283 @@ impl<'a> IMemoryMapper for OffsetPageTable<'a> {
         virt_addr: *const u8,
         size: usize,
         permissions: MapperPermissions,
+        hints: MapperHints,
     ) -> Result<(), &'static str> {
         debug_assert!(size % 4096 == 0, "Size must be page aligned");
 
-        let mut perms: PageTableFlags = PageTableFlags::PRESENT;
-        if !permissions.contains(MapperPermissions::EXECUTE) {
-            perms |= PageTableFlags::NO_EXECUTE;
-        }
+        let flags = page_table_flags(permissions);
+        map_pages(self, virt_addr as u64, None, size, flags, hints)
+    }
 
-        if permissions.contains(MapperPermissions::WRITE) {
-            perms |= PageTableFlags::WRITABLE;
-        }
+    fn unmap_range(&mut self, virt_addr: *const u8, size: usize) -> Result<(), &'static str> {
+        let start = VirtAddr::from_ptr(virt_addr);
+        unmap_pages(self, start, size, |addr, frame| {
+            x86_64::instructions::tlb::flush(addr);
+            unsafe { deallocate_mapped_frame(frame) };
+        })
+    }
+
+    fn virt_to_phys(&mut self, virt_addr: *const ()) -> Option<*const ()> {
+        let virt_addr = VirtAddr::from_ptr(virt_addr);
+        let phys_addr = self.translate_addr(virt_addr)?;
+        Some(phys_addr.as_u64() as *const ())
+    }
+}
+
+fn page_table_flags(permissions: MapperPermissions) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if !permissions.contains(MapperPermissions::EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    if permissions.contains(MapperPermissions::WRITE) {
+        flags |= PageTableFlags::WRITABLE;
+    }
 
-        if permissions.contains(MapperPermissions::RING_3) {
-            perms |= PageTableFlags::USER_ACCESSIBLE;
+    if permissions.contains(MapperPermissions::RING_3) {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+
+    flags
+}
+
+/// Map `[virt_addr, virt_addr + size)` with the largest pages that `hints` allow. The memory
+/// starts at `phys_addr` or is backed by newly allocated zeroed frames if it is `None`.
+/// Huge pages that cannot be mapped fall back to smaller pages. On an error, the pages mapped
+/// so far are unmapped again.
+fn map_pages(
+    opt: &mut OffsetPageTable<'_>,
+    virt_addr: u64,
+    phys_addr: Option<u64>,
+    size: usize,
+    flags: PageTableFlags,
+    hints: MapperHints,
+) -> Result<(), &'static str> {
+    // Kernel mappings are shared by all the page tables. See [`pcid::enable`].
+    let flags = if crate::arch::is_kernel_mode(virt_addr) {
+        flags | PageTableFlags::GLOBAL
+    } else {
+        flags
+    };
+
+    let end = virt_addr + size as u64;
+    let mut addr = virt_addr;
+    while addr < end {
+        let phys = phys_addr.map(|phys| phys + (addr - virt_addr));
+        match map_largest_page(opt, addr, end, phys, flags, hints) {
+            Ok(page_size) => addr += page_size,
+            Err(error) => {
+                let mapped = (addr - virt_addr) as usize;
+                unmap_pages(opt, VirtAddr::new(virt_addr), mapped, |page, frame| {
+                    x86_64::instructions::tlb::flush(page);
+                    if phys_addr.is_none() {
+                        unsafe { deallocate_mapped_frame(frame) };
+                    }
+                })?;
+                return Err(error);
+            }
         }
+    }
 
-        let page_range: PageRange = {
-            let start_page = Page::<Size4KiB>::from_start_address(VirtAddr::from_ptr(virt_addr))
-                .expect("start addr is not aligned");
-            let end_page =
-                Page::<Size4KiB>::from_start_address(VirtAddr::new(virt_addr as u64 + size as u64))
-                    .unwrap();
-            Page::range(start_page, end_page)
-        };
-        let _num_pages = page_range.count();
+    Ok(())
+}
 
-        let mut frame_allocator = super::frame_allocator::get_frame_allocator_zeroed();
-        for page in page_range {
-            let _s: *const u8 = page.start_address().as_ptr();
-            let frame = frame_allocator
-                .allocate_frame()
-                .expect("Cannot allocate frame");
-            unsafe {
-                self.map_to(page, frame, perms, &mut frame_allocator)
-                    .expect("Mapping failed.")
-                    .flush();
+/// Map the largest page at `addr` that `hints` allow and that ends before `end`. Returns the
+/// size of the mapped page.
+fn map_largest_page(
+    opt: &mut OffsetPageTable<'_>,
+    addr: u64,
+    end: u64,
+    phys_addr: Option<u64>,
+    flags: PageTableFlags,
+    hints: MapperHints,
+) -> Result<u64, &'static str> {
+    let fits = |page_size: u64| {
+        addr % page_size == 0
+            && phys_addr.unwrap_or(0) % page_size == 0
+            && end - addr >= page_size
+    };
+
+    if hints.contains(MapperHints::HUGE_1GIB)
+        && fits(Size1GiB::SIZE)
+        && map_page::<Size1GiB>(opt, addr, phys_addr, flags).is_ok()
+    {
+        return Ok(Size1GiB::SIZE);
+    }
+
+    if hints.contains(MapperHints::HUGE_2MIB)
+        && fits(Size2MiB::SIZE)
+        && map_page::<Size2MiB>(opt, addr, phys_addr, flags).is_ok()
+    {
+        return Ok(Size2MiB::SIZE);
+    }
+
+    map_page::<Size4KiB>(opt, addr, phys_addr, flags)?;
+    Ok(Size4KiB::SIZE)
+}
+
+/// Map the page of size `S` at `addr` to `phys_addr` or to a newly allocated zeroed frame if it
+/// is `None`.
+fn map_page<'a, S: PageSize>(
+    opt: &mut OffsetPageTable<'a>,
+    addr: u64,
+    phys_addr: Option<u64>,
+    flags: PageTableFlags,
+) -> Result<(), &'static str>
+where
+    OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(VirtAddr::new(addr))
+        .map_err(|_| "start addr is not aligned")?;
+    let frame = match phys_addr {
+        Some(phys) => PhysFrame::<S>::from_start_address(PhysAddr::new(phys))
+            .map_err(|_| "Physical Frame creation failed.")?,
+        None => frame_allocator::allocate_sized_frame_zeroed()
+            .ok_or(frame_allocator::ALLOCATION_FAILED)?,
+    };
+
+    let mut table_allocator = frame_allocator::get_frame_allocator_zeroed();
+    match unsafe { Mapper::<S>::map_to(opt, page, frame, flags, &mut table_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(error) => {
+            if phys_addr.is_none() {
+                unsafe { frame_allocator::deallocate_sized_frame(frame) };
+            }
+            match error {
+                MapToError::FrameAllocationFailed => Err(frame_allocator::ALLOCATION_FAILED),
+                _ => Err("Mapping failed."),
             }
         }
+    }
+}
 
-        Ok(())
+/// Unmap the pages in `[start, start + size)` without flushing the TLB. Huge pages that are
+/// only partly in the range are split first. `release` is called with the start address and
+/// the frame of every unmapped page.
+fn unmap_pages(
+    opt: &mut OffsetPageTable<'_>,
+    start: VirtAddr,
+    size: usize,
+    mut release: impl FnMut(VirtAddr, MappedFrame),
+) -> Result<(), &'static str> {
+    if !start.is_aligned(Size4KiB::SIZE) || size % globals::PAGE_SIZE != 0 {
+        return Err("start addr is no aligned");
     }
 
-    fn unmap_range(&mut self, virt_addr: *const u8, size: usize) -> Result<(), &'static str> {
-        let page_range: PageRange = {
-            let start_page = Page::<Size4KiB>::from_start_address(VirtAddr::from_ptr(virt_addr))
-                .map_err(|_| "start addr is no aligned")?;
-            let end_page =
-                Page::<Size4KiB>::from_start_address(VirtAddr::new(virt_addr as u64 + size as u64))
-                    .map_err(|_| "start addr is no aligned")?;
-            Page::range(start_page, end_page)
+    let end = start.as_u64() + size as u64;
+    let mut addr = start;
+    while addr.as_u64() < end {
+        let page_size = match opt.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => frame.size(),
+            _ => {
+                addr += Size4KiB::SIZE;
+                continue;
+            }
         };
 
-        let mut deallocator = super::frame_allocator::get_frame_deallocator();
-        for page in page_range {
-            self.unmap(page).map_or((), |frame| {
-                frame.1.flush();
-                unsafe { deallocator.deallocate_frame(frame.0) };
-            });
+        if !addr.is_aligned(page_size) || end - addr.as_u64() < page_size {
+            split_huge_page(opt.level_4_table(), addr)?;
+            continue;
         }
 
-        Ok(())
+        let frame = if page_size == Size1GiB::SIZE {
+            let (frame, flush) = opt
+                .unmap(Page::<Size1GiB>::containing_address(addr))
+                .map_err(|_| "Cannot unmap page")?;
+            flush.ignore();
+            MappedFrame::Size1GiB(frame)
+        } else if page_size == Size2MiB::SIZE {
+            let (frame, flush) = opt
+                .unmap(Page::<Size2MiB>::containing_address(addr))
+                .map_err(|_| "Cannot unmap page")?;
+            flush.ignore();
+            MappedFrame::Size2MiB(frame)
+        } else {
+            let (frame, flush) = opt
+                .unmap(Page::<Size4KiB>::containing_address(addr))
+                .map_err(|_| "Cannot unmap page")?;
+            flush.ignore();
+            MappedFrame::Size4KiB(frame)
+        };
+        release(addr, frame);
+        addr += page_size;
     }
 
-    fn virt_to_phys(&mut self, virt_addr: *const ()) -> Option<*const ()> {
-        let virt_addr = VirtAddr::from_ptr(virt_addr);
-        let phys_addr = self.translate_addr(virt_addr)?;
-        Some(phys_addr.as_u64() as *const ())
+    Ok(())
+}
+
+/// Free the frame of an unmapped page.
+///
+/// # Safety
+/// No page table may map the frame anymore.
+unsafe fn deallocate_mapped_frame(frame: MappedFrame) {
+    unsafe {
+        match frame {
+            MappedFrame::Size4KiB(frame) => frame_allocator::deallocate_sized_frame(frame),
+            MappedFrame::Size2MiB(frame) => frame_allocator::deallocate_sized_frame(frame),
+            MappedFrame::Size1GiB(frame) => frame_allocator::deallocate_sized_frame(frame),
+        }
+    }
+}
+
+/// Round `size` up to whole pages. Fails with [`INVALID_SIZE`] for 0 and for sizes that
+/// overflow when they are rounded up.
+fn page_aligned_size(size: usize) -> Result<usize, &'static str> {
+    if size == 0 {
+        return Err(INVALID_SIZE);
+    }
+    size
+        .checked_add(globals::PAGE_SIZE - 1)
+        .map(|size| align_down(size, globals::PAGE_SIZE))
+        .ok_or(INVALID_SIZE)
+}
+
+/// Split the huge page that maps `addr` in the level 4 table `table` into smaller pages.
+fn split_huge_page(table: &mut PageTable, addr: VirtAddr) -> Result<(), &'static str> {
+    let mut table = table;
+    let levels = [(4, addr.p4_index()), (3, addr.p3_index()), (2, addr.p2_index())];
+    for (level, index) in levels.iter() {
+        let entry = &mut table[*index];
+        if entry.is_unused() {
+            return Err(PAGE_NOT_MAPPED);
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return split_huge_entry(entry, *level);
+        }
+
+        let next = entry.addr().as_u64() + globals::MEM_MAP_OFFSET_LOCATION;
+        table = unsafe { &mut *(next as *mut PageTable) };
+    }
+
+    Err("Page is not a huge page")
+}
+
+/// Replace the huge page in `entry` with a table of 512 pages of the next smaller size that map
+/// the same memory. `level` is the level of the table that holds `entry`. The frames of the
+/// smaller pages are freed one by one later. The buddy allocator merges them again.
+fn split_huge_entry(entry: &mut PageTableEntry, level: u8) -> Result<(), &'static str> {
+    let flags = entry.flags();
+    let (child_size, child_flags) = match level {
+        3 => (Size2MiB::SIZE, flags),
+        2 => (Size4KiB::SIZE, flags - PageTableFlags::HUGE_PAGE),
+        _ => return Err("Invalid level for a huge page"),
+    };
+
+    let frame = frame_allocator::get_frame_allocator()
+        .allocate_frame()
+        .ok_or(frame_allocator::ALLOCATION_FAILED)?;
+    let table_addr = frame.start_address().as_u64() + globals::MEM_MAP_OFFSET_LOCATION;
+    let table = unsafe { &mut *(table_addr as *mut PageTable) };
+    let start = entry.addr().as_u64();
+    for (index, child) in table.iter_mut().enumerate() {
+        child.set_addr(PhysAddr::new(start + index as u64 * child_size), child_flags);
     }
+
+    // Permissions of the parent entries are combined with the ones of the pages. So, keeping
+    // the flags keeps the permissions of the pages.
+    entry.set_frame(frame, flags - PageTableFlags::HUGE_PAGE - PageTableFlags::GLOBAL);
+    Ok(())
 }
@@ -1,10 +1,27 @@
 pub mod cpu_local;
 pub mod frame_allocator;
 pub mod kernel_page_table;
+pub mod oom;
+pub mod pcid;
+#[cfg(feature = "self-checks")]
+pub mod self_checks;
+pub mod tlb;
 
-use x86_64::{structures::paging::PageTable, VirtAddr};
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use super::globals;
+use alloc::sync::Arc;
+use moondust_utils::sync::mutex::Mutex;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use self::kernel_page_table::KernelPageTable;
+use super::{cpu_locals, globals};
+
+/// Physical address of the level 4 table used by cores that are not running user threads.
+static KERNEL_ONLY_TABLE: AtomicU64 = AtomicU64::new(0);
 
 /// Returns a mutable reference to the active level 4 table.
 ///
@@ -33,3 +50,34 @@ pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static
 pub unsafe fn active_level_4_table_default() -> &'static mut PageTable {
     unsafe { active_level_4_table(VirtAddr::new(globals::MEM_MAP_OFFSET_LOCATION)) }
 }
+
+/// Store the active level 4 table as the table used by cores that are not running user
+/// threads. This is called once on the BSP after the kernel mappings are ready.
+pub fn save_kernel_only_table() {
+    let (frame, _) = Cr3::read();
+    KERNEL_ONLY_TABLE.store(frame.start_address().as_u64(), Ordering::SeqCst);
+}
+
+/// Switch the current core to the kernel only table and take the page table of the last
+/// user thread that ran on this core. Threads move between cores. So, without this, a core
+/// keeps the address space of a process alive until it runs another user thread.
+///
+/// The returned page table can be the last reference to the address space. Dropping it frees
+/// all of its memory. So, the caller should drop it with the interrupts enabled.
+pub fn activate_kernel_only_table() -> Option<Arc<Mutex<KernelPageTable>>> {
+    let phys = KERNEL_ONLY_TABLE.load(Ordering::SeqCst);
+    if phys == 0 {
+        return None;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let (current, _) = Cr3::read();
+        if current.start_address().as_u64() != phys {
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+            // The kernel only table has no user mappings. So, its TLB entries are always kept.
+            unsafe { pcid::switch(frame, pcid::KERNEL_PCID, 0, 0) };
+        }
+        tlb::set_active_cpus(None);
+        cpu_locals::CURRENT_PAGE_TABLE.replace(None)
+    })
+}
@@ -0,0 +1,52 @@
+//! Out of memory policy. When a page fault cannot be resolved because there are no free frames
+//! left, the process with the most virtual memory is killed. Its frames are freed once all its
+//! threads have exited. Threads of the process that are blocked in a syscall are woken up so
+//! that they exit as well.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::arch::process::{
+    process_table::{self, Process},
+    state::ExitStatus,
+};
+
+/// Number of page faults in a row that a thread tries to resolve by killing processes. The
+/// thread exits with [`ExitStatus::OutOfMemory`] after that.
+pub const MAX_RETRIES: usize = 3;
+
+/// Free memory by killing the process with the most virtual memory. The page table of the
+/// caller must not be locked. Returns the killed process or `None` if there is no process
+/// left to kill. The caller can wait for the process to release its memory.
+pub async fn kill_largest() -> Option<Arc<Process>> {
+    let processes: Vec<_> = process_table::list()
+        .into_iter()
+        .filter_map(|process| process.page_table().map(|page_table| (process, page_table)))
+        .collect();
+
+    let mut largest: Option<(usize, &Arc<Process>)> = None;
+    for (process, page_table) in &processes {
+        // Killing more processes before the last one has released its memory would kill
+        // processes for nothing.
+        if process.is_killed() {
+            return Some(process.clone());
+        }
+
+        let size = page_table.lock().await.vmem_allocated();
+        if largest.map_or(true, |(largest_size, _)| size > largest_size) {
+            largest = Some((size, process));
+        }
+    }
+
+    match largest {
+        Some((size, process)) => {
+            warn!(
+                target: "oom",
+                "Out of memory. Killing process {} with {} bytes of memory",
+                process.process_id(), size
+            );
+            process.kill(ExitStatus::OutOfMemory);
+            Some(process.clone())
+        }
+        None => None,
+    }
+}
@@ -0,0 +1,105 @@
+//! Process context identifiers (PCIDs). With PCIDs, the TLB keeps the entries of several
+//! address spaces and switching the page table doesn't flush it.
+//!
+//! Each [`super::kernel_page_table::KernelPageTable`] gets a PCID derived from its process id.
+//! Process ids are reused and there can be more processes than PCIDs. So, every core
+//! remembers which page table used each PCID last and the TLB generation of that page table.
+//! The TLB entries are only kept on a switch if both still match.
+
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use alloc::{boxed::Box, vec};
+use x86_64::{
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::paging::PhysFrame,
+};
+
+/// Number of PCIDs supported by the CPU.
+const PCID_COUNT: usize = 4096;
+
+/// PCID of the kernel only page table. See [`super::activate_kernel_only_table`].
+pub const KERNEL_PCID: u16 = 0;
+
+/// Bit of CR3 that keeps the TLB entries of the new PCID.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The id and the TLB generation of the page table that used each PCID last on this core.
+/// Allocated on the first switch so that the TLS of every core stays small.
+#[thread_local]
+static mut OWNERS: Option<Box<[(u64, u64)]>> = None;
+
+/// Enable PCIDs on the current core if the CPU supports them. This is called on every core
+/// while CR3 still has PCID 0.
+///
+/// Global pages are enabled as well. The kernel mappings are the same in all the page tables
+/// and are marked global. So, they are not cached once per PCID and `invlpg` removes them
+/// for all the PCIDs.
+pub fn enable() {
+    let mut cr4 = Cr4::read();
+    cr4 |= Cr4Flags::PAGE_GLOBAL;
+    unsafe { Cr4::write(cr4) };
+
+    let supported = unsafe { __cpuid(1) }.ecx & (1 << 17) != 0;
+    if !supported {
+        info!(target: "pcid", "PCIDs are not supported. The TLB is flushed on every switch.");
+        return;
+    }
+
+    let mut cr4 = Cr4::read();
+    cr4 |= Cr4Flags::PCID;
+    unsafe { Cr4::write(cr4) };
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// An unique id for a page table. Unlike process ids, these are never reused. 0 is the
+/// kernel only page table.
+pub fn new_table_id() -> u64 {
+    NEXT_TABLE_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// The PCID for the page table of a process. Processes share a PCID once the ids run out.
+pub fn pcid_for_process(process_id: usize) -> u16 {
+    (process_id % (PCID_COUNT - 1)) as u16 + 1
+}
+
+/// Load the level 4 table in `frame` on this core. The TLB entries tagged with `pcid` are
+/// kept if they were created by the page table `table_id` at `tlb_generation`.
+///
+/// # Safety
+/// The frame must hold a valid level 4 table with the kernel mappings.
+pub unsafe fn switch(frame: PhysFrame, pcid: u16, table_id: u64, tlb_generation: u64) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(frame, flags) };
+        return;
+    }
+
+    let owners = unsafe { OWNERS.get_or_insert_with(|| vec![(0, 0); PCID_COUNT].into()) };
+    let owner = &mut owners[pcid as usize];
+    let keep = *owner == (table_id, tlb_generation);
+    *owner = (table_id, tlb_generation);
+
+    let mut value = frame.start_address().as_u64() | pcid as u64;
+    if keep {
+        value |= CR3_NO_FLUSH;
+    }
+    unsafe { asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags)) };
+}
+
+/// Flush all the non global TLB entries of the current address space on this core.
+pub fn flush_current() {
+    unsafe {
+        let value: u64;
+        asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
+        // Writing CR3 without the no flush bit flushes the entries of its PCID.
+        let value = value & !CR3_NO_FLUSH;
+        asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
@@ -0,0 +1,107 @@
+//! Checks of the memory management that run at boot when the kernel is built with the
+//! `self-checks` feature. They assert on the state of the physical memory allocator. So, they
+//! run before any other task allocates memory.
+
+use alloc::{boxed::Box, sync::Arc};
+use x86_64::structures::paging::{PageSize, PageTable, Size2MiB};
+
+use super::{
+    frame_allocator,
+    kernel_page_table::{KernelPageTable, INVALID_SIZE},
+};
+use crate::{
+    arch::globals,
+    common::memory::paging::{IMemoryMapper, MapperHints, MapperPermissions},
+};
+
+/// Run all the checks. Panics if one of them fails.
+pub fn run() {
+    check_teardown();
+    check_futex_fork();
+    check_oversized_mappings();
+}
+
+/// An empty page table for the checks. It is never activated and no process has the id 0.
+fn page_table() -> KernelPageTable {
+    KernelPageTable::new(Box::new(PageTable::new()), 0)
+}
+
+fn user_permissions() -> MapperPermissions {
+    MapperPermissions::READ | MapperPermissions::WRITE | MapperPermissions::RING_3
+}
+
+/// Create a page table with user mappings of all the page sizes, drop it and check that all
+/// the frames, including the ones of the tables, are back in the physical memory allocator.
+fn check_teardown() {
+    let before = frame_allocator::allocated_bytes();
+    {
+        let mut page_table = page_table();
+        let start = globals::USER_HEAP_START;
+        let size = 2 * Size2MiB::SIZE as usize + 3 * globals::PAGE_SIZE;
+        page_table
+            .map_with_alloc(start as _, size, user_permissions(), MapperHints::HUGE_2MIB)
+            .expect("Cannot map test memory");
+
+        // Splits the first huge page.
+        page_table
+            .unmap_range((start + globals::PAGE_SIZE) as _, globals::PAGE_SIZE)
+            .expect("Cannot unmap test memory");
+    }
+
+    let after = frame_allocator::allocated_bytes();
+    assert_eq!(
+        before, after,
+        "Page table teardown leaked frames. Allocated bytes before: {}, after: {}",
+        before, after
+    );
+    info!(target: "self_checks", "Page table teardown frees all the frames");
+}
+
+/// Wait on a futex, fork the page table and wake the futex. The waker breaks copy-on-write
+/// on the page of the word and must still find the futex of the waiting thread.
+fn check_futex_fork() {
+    let mut parent = page_table();
+    let addr = globals::USER_HEAP_START as u64 + 8;
+    parent
+        .reserve(globals::USER_HEAP_START as _, globals::PAGE_SIZE, user_permissions())
+        .expect("Cannot reserve test memory");
+
+    let (wait_key, waiting, _) = parent.get_futex(addr).expect("Cannot wait on test futex");
+    let child = parent
+        .fork(Box::new(PageTable::new()), 0)
+        .expect("Cannot fork test page table");
+    let (wake_key, waking, _) = parent.get_futex(addr).expect("Cannot wake test futex");
+    assert!(
+        wait_key == wake_key && Arc::ptr_eq(&waiting, &waking),
+        "Futex changed after fork. Key of the waiter: {:#x}, key of the waker: {:#x}",
+        wait_key,
+        wake_key
+    );
+
+    drop(child);
+    info!(target: "self_checks", "Futexes survive a fork of the waiting process");
+}
+
+/// Ask for user memory with sizes that overflow when they are rounded up to whole pages and
+/// check that they are refused with [`INVALID_SIZE`].
+fn check_oversized_mappings() {
+    let mut page_table = page_table();
+    let sizes = [usize::MAX, usize::MAX - 1, usize::MAX - globals::PAGE_SIZE + 2];
+    for size in sizes.iter() {
+        let start = globals::USER_MMAP_START as u64;
+        let anywhere = page_table.map_anonymous(0, *size, user_permissions());
+        let fixed = page_table.map_anonymous(start, *size, user_permissions());
+        let heap = page_table.map_more_user_heap(*size);
+        let errors = [anywhere.err(), fixed.err(), heap.err()];
+        assert!(
+            errors.iter().all(|error| *error == Some(INVALID_SIZE)),
+            "Oversized mapping of {:#x} bytes was not refused: {:?}, {:?}, {:?}",
+            size,
+            anywhere,
+            fixed,
+            heap
+        );
+    }
+    assert_eq!(page_table.vmem_allocated(), 0, "Oversized mappings reserved memory");
+    info!(target: "self_checks", "Oversized mappings are refused");
+}
@@ -0,0 +1,155 @@
+//! TLB shootdowns. A page table can be active on several cores at the same time. When a
+//! mapping is removed or its permissions are reduced, the other cores must flush their TLB
+//! too. The core that changes the mapping sends an IPI to the other cores and waits until all
+//! of them have flushed.
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::{instructions::tlb, structures::idt::InterruptStackFrame, VirtAddr};
+
+use crate::arch::{
+    cpu_locals, globals,
+    interrupts::apic::{self, Ipi},
+};
+
+/// Ranges with more pages than this flush the whole TLB instead.
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+/// A set of cores identified by their [`cpu_locals::PROCESSOR_ID`].
+#[derive(Debug)]
+pub struct CpuSet([AtomicU64; globals::MAX_CORE_COUNT / 64]);
+
+impl CpuSet {
+    pub const fn new() -> Self {
+        const EMPTY: AtomicU64 = AtomicU64::new(0);
+        Self([EMPTY; globals::MAX_CORE_COUNT / 64])
+    }
+
+    pub fn insert(&self, cpu: usize) {
+        self.0[cpu / 64].fetch_or(1 << (cpu % 64), Ordering::SeqCst);
+    }
+
+    /// Remove `cpu` from the set. Returns true if it was in the set.
+    pub fn remove(&self, cpu: usize) -> bool {
+        let bit = 1 << (cpu % 64);
+        self.0[cpu / 64].fetch_and(!bit, Ordering::SeqCst) & bit != 0
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.0[cpu / 64].load(Ordering::SeqCst) & (1 << (cpu % 64)) != 0
+    }
+
+    /// Iterate over a snapshot of the cores in the set.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(index, word)| {
+            let word = word.load(Ordering::SeqCst);
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| index * 64 + bit)
+        })
+    }
+}
+
+/// Cores that have to handle the current shootdown.
+static PENDING: CpuSet = CpuSet::new();
+
+/// Number of cores that have not handled the current shootdown yet.
+static PENDING_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Range of the current shootdown.
+static REQUEST_START: AtomicU64 = AtomicU64::new(0);
+static REQUEST_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Only one shootdown is sent at a time.
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Cores of the page table that is active on this core.
+#[thread_local]
+static ACTIVE_CPUS: RefCell<Option<Arc<CpuSet>>> = RefCell::new(None);
+
+/// Record that the page table with the cores `cpus` is now active on this core. This is called
+/// after the page table is loaded. `cpus` must already contain this core so that shootdowns
+/// sent in between are not missed. `None` is for page tables without user mappings.
+pub fn set_active_cpus(cpus: Option<Arc<CpuSet>>) {
+    let current = cpu_locals::PROCESSOR_ID.get();
+    let previous = ACTIVE_CPUS.replace(cpus.clone());
+    if let Some(previous) = previous {
+        let same = matches!(&cpus, Some(cpus) if Arc::ptr_eq(cpus, &previous));
+        if !same {
+            previous.remove(current);
+        }
+    }
+}
+
+/// Flush `pages` pages starting at `start` on all the cores in `cpus`. This returns after all
+/// of them have flushed.
+pub fn shootdown(cpus: &CpuSet, start: VirtAddr, pages: u64) {
+    let current = cpu_locals::PROCESSOR_ID.get();
+    if cpus.contains(current) {
+        flush_local(start, pages);
+    }
+
+    // The set can change while the IPIs are sent. So, work on a snapshot.
+    let targets: Vec<usize> = cpus.iter().filter(|cpu| *cpu != current).collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    // Keep handling the shootdowns of other cores while waiting. They might be waiting for
+    // this core with the interrupts disabled.
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+        handle_shootdown();
+        core::hint::spin_loop();
+    };
+
+    REQUEST_START.store(start.as_u64(), Ordering::SeqCst);
+    REQUEST_PAGES.store(pages, Ordering::SeqCst);
+    PENDING_COUNT.store(targets.len(), Ordering::SeqCst);
+    for cpu in &targets {
+        PENDING.insert(*cpu);
+    }
+    for cpu in targets {
+        apic::send_ipi(cpu, Ipi::TlbShootdown);
+    }
+
+    while PENDING_COUNT.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Flush the TLB of this core if the current shootdown is for it.
+fn handle_shootdown() {
+    if PENDING.remove(cpu_locals::PROCESSOR_ID.get()) {
+        let start = VirtAddr::new(REQUEST_START.load(Ordering::SeqCst));
+        flush_local(start, REQUEST_PAGES.load(Ordering::SeqCst));
+        PENDING_COUNT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > FLUSH_ALL_THRESHOLD {
+        super::pcid::flush_current();
+        return;
+    }
+
+    for page in 0..pages {
+        tlb::flush(start + page * globals::PAGE_SIZE as u64);
+    }
+}
+
+/// Handler for [`Ipi::TlbShootdown`].
+pub extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: &mut InterruptStackFrame) {
+    handle_shootdown();
+    unsafe {
+        let lapic = &mut cpu_locals::LAPIC;
+        let eoi = lapic.end_of_interrupt();
+        eoi.signal();
+    }
+}
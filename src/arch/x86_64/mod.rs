@@ -5,6 +5,7 @@ pub mod interrupts;
 pub mod memory;
 pub mod process;
 pub mod serial;
+pub mod time;
 
 use moondust_utils::buddy_system_allocator;
 use x86_64::VirtAddr;
@@ -41,6 +42,16 @@ pub mod cpu_locals {
 
     #[thread_local]
     pub static CURRENT_THREAD_ID: Cell<usize> = Cell::new(0);
+
+    /// Index of the current core. The BSP is 0 and the APs follow in the order they boot.
+    /// Unlike [`PROCESSOR_ID`], the indices have no gaps.
+    #[thread_local]
+    pub static CPU_INDEX: Cell<usize> = Cell::new(0);
+
+    /// Index of the current core. See [`CPU_INDEX`].
+    pub fn cpu_index() -> usize {
+        CPU_INDEX.get()
+    }
 }
 
 pub fn is_kernel_mode(addr: u64) -> bool {
@@ -50,3 +61,8 @@ pub fn is_kernel_mode(addr: u64) -> bool {
 
     (addr & (1 << 62)) > 0
 }
+
+/// Returns true if the address is a canonical address in the user half.
+pub fn is_user_address(addr: u64) -> bool {
+    VirtAddr::try_new(addr).is_ok() && !is_kernel_mode(addr)
+}
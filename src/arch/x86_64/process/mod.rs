@@ -7,21 +7,31 @@ use core::{
 use alloc::{sync::Arc, task::Wake};
 use futures_lite::{pin, Future};
 
+use crate::arch::{
+    cpu_locals,
+    interrupts::apic::{self, Ipi},
+};
+
 mod thread;
 pub use thread::*;
+pub mod process_table;
 pub mod state;
 pub mod user_future;
 
-#[thread_local]
-static SHOULD_WAKE: AtomicBool = AtomicBool::new(false);
-
 /// Run the future until it is complete while waiting when busy.
 /// This waits when the task is pending until another interrupt to
 /// the system awakens this.
 pub fn block_on<T>(task: impl Future<Output = T>) -> T {
-    let waker = SchedulerWaker::new();
+    let scheduler_waker = Arc::new(SchedulerWaker {
+        should_wake: AtomicBool::new(false),
+        halted: AtomicBool::new(false),
+        processor_id: cpu_locals::PROCESSOR_ID.get(),
+    });
+    let waker = Waker::from(scheduler_waker.clone());
     pin!(task);
     loop {
+        crate::common::time::wake_expired_timers();
+
         let mut context = Context::from_waker(&waker);
         match task.as_mut().poll(&mut context) {
             Poll::Ready(v) => {
@@ -31,31 +41,59 @@ pub fn block_on<T>(task: impl Future<Output = T>) -> T {
         };
 
         x86_64::instructions::interrupts::disable();
-        if SHOULD_WAKE.swap(false, Ordering::SeqCst) {
+        // A waker on another core sends an IPI once this is set. If the IPI comes before the
+        // `hlt`, it stays pending until the interrupts are enabled and wakes the core up.
+        scheduler_waker.halted.store(true, Ordering::SeqCst);
+        if scheduler_waker.should_wake.swap(false, Ordering::SeqCst) {
+            scheduler_waker.halted.store(false, Ordering::SeqCst);
             x86_64::instructions::interrupts::enable();
             continue;
         } else {
+            // The core is idle. Don't keep the address space of the last user thread alive.
+            // Dropping it can free the whole address space. That takes long. So, it is done
+            // with the interrupts enabled and the task is polled again before halting.
+            if let Some(page_table) = crate::arch::memory::activate_kernel_only_table() {
+                scheduler_waker.halted.store(false, Ordering::SeqCst);
+                x86_64::instructions::interrupts::enable();
+                drop(page_table);
+                continue;
+            }
+
             trace!(target:"block_on", "Sleeping");
             x86_64::instructions::interrupts::enable_and_hlt();
+            scheduler_waker.halted.store(false, Ordering::SeqCst);
         }
     }
 }
 
 /// Waker used to wake the blocked execution on a signal.
-struct SchedulerWaker {}
+/// The waker can be called from any core. So, the flag belongs to the waker and not to the
+/// core that calls it. A halted core is woken up with an IPI.
+struct SchedulerWaker {
+    should_wake: AtomicBool,
+    /// Set while the core is halted or about to halt.
+    halted: AtomicBool,
+    /// The core that runs [`block_on`].
+    processor_id: usize,
+}
 
 impl SchedulerWaker {
-    pub fn new() -> Waker {
-        Waker::from(Arc::new(SchedulerWaker {}))
+    fn notify(&self) {
+        self.should_wake.store(true, Ordering::SeqCst);
+        if self.halted.load(Ordering::SeqCst)
+            && self.processor_id != cpu_locals::PROCESSOR_ID.get()
+        {
+            apic::send_ipi(self.processor_id, Ipi::Wakeup);
+        }
     }
 }
 
 impl Wake for SchedulerWaker {
     fn wake(self: Arc<Self>) {
-        SHOULD_WAKE.store(true, Ordering::SeqCst);
+        self.notify();
     }
 
     fn wake_by_ref(self: &Arc<Self>) {
-        SHOULD_WAKE.store(true, Ordering::SeqCst);
+        self.notify();
     }
 }
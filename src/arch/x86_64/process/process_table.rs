@@ -0,0 +1,237 @@
+//! Processes and the table of all the processes.
+
+use core::mem;
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use futures_lite::{future, Future};
+use moondust_utils::{
+    id_generator::IdGenerator,
+    sync::{mutex::Mutex, once::AsyncOnce},
+};
+
+use super::state::ExitStatus;
+use crate::arch::memory::kernel_page_table::KernelPageTable;
+
+static PROCESS_ID_GENERATOR: IdGenerator = IdGenerator::new();
+
+/// All the processes keyed by their id. A process is in the table until it is dropped. That is
+/// when all its threads are gone and no other process has a handle to it. A finished child
+/// stays until its parent waits for it or finishes itself.
+static PROCESSES: spin::Mutex<BTreeMap<usize, Weak<Process>>> =
+    spin::Mutex::new(BTreeMap::new());
+
+/// A process. Owns the address space that its threads run in.
+#[derive(Debug)]
+pub struct Process {
+    process_id: usize,
+    /// Id of the process that created this one.
+    parent_id: Option<usize>,
+    /// Address space of the process. Released when the last thread finishes. The memory is
+    /// freed once the threads are dropped.
+    page_table: spin::Mutex<Option<Arc<Mutex<KernelPageTable>>>>,
+    state: spin::Mutex<ProcessState>,
+    /// Set when the process is killed to the exit status of its threads. They exit the next
+    /// time they are scheduled.
+    killed: AsyncOnce<ExitStatus>,
+    exit: AsyncOnce<ExitStatus>,
+}
+
+#[derive(Debug, Default)]
+struct ProcessState {
+    /// Threads that have not finished yet.
+    threads: BTreeSet<usize>,
+    /// The first thread of the process.
+    main_thread: Option<usize>,
+    /// Exit status of the main thread or of the first thread that finished.
+    status: Option<ExitStatus>,
+    handles: BTreeMap<u64, Handle>,
+    next_handle: u64,
+}
+
+/// A kernel object that a process refers to.
+#[derive(Debug, Clone)]
+pub enum Handle {
+    /// A child process. The child is kept in the process table as long as the handle exists.
+    Process(Arc<Process>),
+}
+
+impl Process {
+    /// Create a process with the page table from `create_page_table`. It gets the id of the
+    /// new process.
+    pub fn new(
+        parent_id: Option<usize>,
+        create_page_table: impl FnOnce(usize) -> Result<KernelPageTable, &'static str>,
+    ) -> Result<Arc<Self>, &'static str> {
+        let process_id = PROCESS_ID_GENERATOR.get_value();
+        let page_table = match create_page_table(process_id) {
+            Ok(page_table) => page_table,
+            Err(reason) => {
+                PROCESS_ID_GENERATOR.return_value(process_id);
+                return Err(reason);
+            }
+        };
+
+        let process = Arc::new(Self {
+            process_id,
+            parent_id,
+            page_table: spin::Mutex::new(Some(Arc::new(Mutex::new(page_table)))),
+            state: spin::Mutex::new(ProcessState::default()),
+            killed: AsyncOnce::new(),
+            exit: AsyncOnce::new(),
+        });
+        PROCESSES
+            .lock()
+            .insert(process_id, Arc::downgrade(&process));
+        Ok(process)
+    }
+
+    pub fn process_id(&self) -> usize {
+        self.process_id
+    }
+
+    pub fn parent_id(&self) -> Option<usize> {
+        self.parent_id
+    }
+
+    /// Get the address space of the process. Returns `None` once the process finished.
+    pub fn page_table(&self) -> Option<Arc<Mutex<KernelPageTable>>> {
+        self.page_table.lock().clone()
+    }
+
+    /// Add a thread to the process. The first thread is the main thread.
+    pub fn add_thread(&self, thread_id: usize) {
+        let mut state = self.state.lock();
+        state.threads.insert(thread_id);
+        state.main_thread.get_or_insert(thread_id);
+    }
+
+    /// Remove a thread from the process. `status` is the exit status of the thread if it ran.
+    /// The process finishes when the last thread is removed. Its exit status is the one of
+    /// the main thread. A process where no thread ran finishes with [`ExitStatus::Killed`].
+    pub fn remove_thread(&self, thread_id: usize, status: Option<ExitStatus>) {
+        let (status, handles) = {
+            let mut state = self.state.lock();
+            if !state.threads.remove(&thread_id) {
+                return;
+            }
+            if let Some(status) = status {
+                if state.status.is_none() || state.main_thread == Some(thread_id) {
+                    state.status = Some(status);
+                }
+            }
+            if !state.threads.is_empty() {
+                return;
+            }
+            let status = state.status.unwrap_or(ExitStatus::Killed);
+            (status, mem::take(&mut state.handles))
+        };
+
+        // Children are dropped outside of the lock. This can be the last reference to them.
+        drop(handles);
+        // The threads still refer to the address space. It is freed once they are dropped.
+        let page_table = self.page_table.lock().take();
+        drop(page_table);
+        info!(target: "process", "Process {} {}", self.process_id, status);
+        self.exit.try_set_result(status);
+    }
+
+    /// Wait until all the threads of the process have finished and get its exit status.
+    pub async fn wait(&self) -> ExitStatus {
+        *self.exit.clone().await
+    }
+
+    /// Get the exit status without waiting. Returns `None` if the process has not finished.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit.try_get().map(|status| *status)
+    }
+
+    /// Kill the process. The threads exit with `status` the next time they are scheduled.
+    /// Threads that are blocked in a syscall are woken up and exit as well. See
+    /// [`Self::unless_killed`]. Only the first kill sets the status.
+    pub fn kill(&self, status: ExitStatus) {
+        self.killed.try_set_result(status);
+    }
+
+    /// Run `future` unless the process is killed first. Returns `None` if it was killed. The
+    /// future is dropped then. This is how blocked syscalls of a killed process are cancelled.
+    pub async fn unless_killed<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        let killed = async {
+            self.killed.clone().await;
+            None
+        };
+        future::or(async { Some(future.await) }, killed).await
+    }
+
+    /// Get the status that the threads exit with if the process was killed.
+    pub fn killed(&self) -> Option<ExitStatus> {
+        self.killed.try_get().map(|status| *status)
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.is_ready()
+    }
+
+    /// Add a handle to the handle table of the process. Returns the handle number.
+    pub fn insert_handle(&self, handle: Handle) -> u64 {
+        let mut state = self.state.lock();
+        let number = state.next_handle;
+        state.next_handle += 1;
+        state.handles.insert(number, handle);
+        number
+    }
+
+    /// Get the child process with `process_id`.
+    pub fn child(&self, process_id: usize) -> Option<Arc<Process>> {
+        let state = self.state.lock();
+        state.handles.values().find_map(|handle| match handle {
+            Handle::Process(child) if child.process_id == process_id => Some(child.clone()),
+            _ => None,
+        })
+    }
+
+    /// Remove the handles to the child process with `process_id`. A finished child is dropped
+    /// once no one else refers to it. That gives its id to new processes.
+    pub fn remove_child(&self, process_id: usize) {
+        let mut removed = Vec::new();
+        {
+            let mut state = self.state.lock();
+            let numbers: Vec<u64> = state
+                .handles
+                .iter()
+                .filter(|(_, handle)| {
+                    matches!(handle, Handle::Process(child) if child.process_id == process_id)
+                })
+                .map(|(number, _)| *number)
+                .collect();
+            for number in numbers {
+                removed.extend(state.handles.remove(&number));
+            }
+        }
+
+        // Children are dropped outside of the lock. This can be the last reference to them.
+        drop(removed);
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // The id is only given to another process after this. So, the entry belongs to this one.
+        PROCESSES.lock().remove(&self.process_id);
+        info!(target: "process", "Released process {}", self.process_id);
+        PROCESS_ID_GENERATOR.return_value(self.process_id);
+    }
+}
+
+/// Get the process with `process_id`.
+pub fn get(process_id: usize) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&process_id).and_then(Weak::upgrade)
+}
+
+/// Get all the processes.
+pub fn list() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().filter_map(Weak::upgrade).collect()
+}
@@ -1,7 +1,15 @@
-use moondust_sys::syscall::{Syscalls, Sysrets};
+use core::fmt::{Debug, Display};
+
+use alloc::boxed::Box;
+use moondust_sys::syscall::{numbers, SyscallResult};
 use moondust_utils::sync::once::AsyncOnce;
+use x86_64::structures::idt::PageFaultErrorCode;
 
-#[derive(Default, Debug)]
+/// Register state of a user thread.
+/// The layout is `#[repr(C)]` because the assembly that resumes a preempted thread reads
+/// the fields by their offsets.
+#[derive(Default, Debug, Clone)]
+#[repr(C)]
 pub struct Registers {
     pub rcx: u64,
     pub rdx: u64,
@@ -52,6 +60,18 @@ impl Registers {
             rflags: 0,
         }
     }
+
+    /// Store the result of a syscall in the registers that are returned to the user.
+    /// See [`moondust_sys::syscall`] for the ABI.
+    pub fn set_syscall_result(&mut self, result: SyscallResult) {
+        let (code, val0, val1) = match result {
+            Ok((val0, val1)) => (0, val0, val1),
+            Err(error) => (error as u64, 0, 0),
+        };
+        self.rax = code;
+        self.rdi = val0;
+        self.rsi = val1;
+    }
 }
 
 /// The state of a thread.
@@ -64,14 +84,139 @@ pub enum ThreadState {
 
     /// Thread has not started
     NotStarted(Registers),
+
+    /// Thread was preempted by the timer while running in user mode.
+    /// All the registers are saved so that the thread can be resumed transparently.
+    Preempted(Registers, Box<FpuState>),
+
+    /// Thread caused a page fault in user mode. The thread can be resumed
+    /// with the registers once the fault is resolved.
+    Faulted(Registers, Box<FpuState>, PageFault),
+}
+
+/// The x87, MMX and SSE registers of a user thread in the format of `fxsave`.
+/// The kernel doesn't use these registers. So, they still have the values of the user thread
+/// when the kernel gets control and only need to be saved before another thread runs.
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The state after `fninit` with all the SSE exceptions masked.
+    pub const fn initial() -> FpuState {
+        let mut area = [0; 512];
+        // FCW is 0x037F.
+        area[0] = 0x7F;
+        area[1] = 0x03;
+        // MXCSR is 0x1F80.
+        area[24] = 0x80;
+        area[25] = 0x1F;
+        FpuState(area)
+    }
+
+    /// Save the registers of the current core.
+    pub fn save() -> Box<FpuState> {
+        let mut state = Box::new(FpuState([0; 512]));
+        unsafe {
+            asm!("fxsave64 [{}]", in(reg) state.0.as_mut_ptr(), options(nostack));
+        }
+        state
+    }
+
+    /// Load the registers of the current core from this state.
+    pub fn restore(&self) {
+        unsafe {
+            asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, readonly));
+        }
+    }
+}
+
+impl Debug for FpuState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("FpuState { .. }")
+    }
+}
+
+/// Information about a page fault caused by a user thread.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The address that was accessed.
+    pub address: u64,
+    /// The instruction pointer of the faulting instruction.
+    pub ip: u64,
+    pub error_code: PageFaultErrorCode,
+}
+
+/// The status with which a thread finished running.
+#[derive(Debug, Clone, Copy)]
+pub enum ExitStatus {
+    /// Thread exited by itself with the given code.
+    Exited(u8),
+
+    /// Thread was terminated because of a page fault.
+    PageFault(PageFault),
+
+    /// Thread was terminated because the stack of `thread_id` overflowed into its guard area.
+    StackOverflow { thread_id: usize, fault: PageFault },
+
+    /// Thread was terminated because its process was killed to free memory.
+    OutOfMemory,
+
+    /// Thread was terminated because its process was killed by another process.
+    Killed,
+}
+
+impl Display for ExitStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ExitStatus::PageFault(fault) => write!(
+                f,
+                "killed by page fault at address {:#x} (ip: {:#x}, error: {:?})",
+                fault.address, fault.ip, fault.error_code
+            ),
+            ExitStatus::StackOverflow { thread_id, fault } => write!(
+                f,
+                "killed by stack overflow in thread {} at address {:#x} (ip: {:#x})",
+                thread_id, fault.address, fault.ip
+            ),
+            ExitStatus::OutOfMemory => write!(f, "killed because the system ran out of memory"),
+            ExitStatus::Killed => write!(f, "killed by another process"),
+        }
+    }
 }
 
 /// A state that denotes the thread state when it is syscall'ed.
+/// The syscall number and the arguments are in the registers as described in
+/// [`moondust_sys::syscall`].
 #[derive(Debug)]
 pub struct SyscallState {
     pub registers: Registers,
-    pub syscall_info: Syscalls<'static>,
+    /// FPU registers of the thread. Restored when the thread returns from the syscall.
+    pub fpu: Box<FpuState>,
 
     pub return_data_awaiter: AsyncOnce<()>,
-    pub return_data: &'static mut Sysrets,
+}
+
+impl SyscallState {
+    /// The syscall number.
+    pub fn number(&self) -> u64 {
+        self.registers.rax
+    }
+
+    /// The arguments of the syscall.
+    pub fn args(&self) -> [u64; 6] {
+        let r = &self.registers;
+        [r.rdi, r.rsi, r.rdx, r.r10, r.r8, r.r9]
+    }
+
+    /// Check if the syscall only waits for something. These are cancelled when the process is
+    /// killed. The others, like `FORK` and `SPAWN`, run to their end so that nothing is left
+    /// half done.
+    pub fn only_waits(&self) -> bool {
+        matches!(
+            self.number(),
+            numbers::JOIN_THREAD | numbers::FUTEX_WAIT | numbers::SLEEP_FOR | numbers::WAIT
+        )
+    }
 }
@@ -1,77 +1,276 @@
-use core::{panic, task::Poll};
+use core::{cmp::max, panic, task::Poll};
 
-use alloc::{boxed::Box, sync::Arc};
-use moondust_utils::{id_generator::IdGenerator, sync::mutex::Mutex};
-use x86_64::structures::paging::PageTable;
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
+use moondust_utils::{
+    id_generator::IdGenerator,
+    sync::{mutex::Mutex, once::AsyncOnce},
+};
+use x86_64::{registers::rflags::RFlags, structures::paging::PageTable};
 
-use crate::arch::memory::kernel_page_table::KernelPageTable;
-use crate::common::memory::paging::{IMemoryMapper, MapperPermissions};
+use crate::arch::memory::{
+    kernel_page_table::{KernelPageTable, PageFaultError},
+    oom,
+};
 use crate::{arch::globals, common::align_up};
 
-use super::state::{Registers, ThreadState};
+use super::{
+    process_table::{Handle, Process},
+    state::{ExitStatus, Registers, ThreadState},
+};
 
 /// A single thread of execution in the kernel.
 #[derive(Debug)]
 pub struct Thread {
     pub thread_id: usize,
+    process: Arc<Process>,
     page_table: Arc<Mutex<KernelPageTable>>,
     pub state: ThreadState,
+    exit: Arc<ThreadExit>,
 }
 
 static THREAD_ID_GENERATOR: IdGenerator = IdGenerator::new();
 
+/// Exit status of a thread that other threads in the same process can wait for.
+#[derive(Debug)]
+struct ThreadExit {
+    process: Weak<Process>,
+    status: AsyncOnce<ExitStatus>,
+}
+
+/// Exit status of the threads that have not been joined yet. Thread ids are reused. So, the
+/// status of a thread that is never joined is replaced when its id is given to a new thread.
+static THREAD_EXITS: spin::Mutex<BTreeMap<usize, Arc<ThreadExit>>> =
+    spin::Mutex::new(BTreeMap::new());
+
 impl Thread {
-    /// Create a new empty process and an empty thread in that process.
-    pub async fn new_empty_process(stack_size: usize) -> Self {
-        let mut thread = Self {
-            thread_id: THREAD_ID_GENERATOR.get_value(),
-            page_table: Arc::new(Mutex::new(KernelPageTable::new(
-                Self::create_new_kernel_only_pagetable_from_current(),
-            ))),
-            state: ThreadState::NotStarted(Registers::default()),
-        };
-        thread.setup_user_stack(stack_size).await;
+    fn new(
+        process: Arc<Process>,
+        page_table: Arc<Mutex<KernelPageTable>>,
+        state: ThreadState,
+    ) -> Self {
+        let thread_id = THREAD_ID_GENERATOR.get_value();
+        let exit = Arc::new(ThreadExit {
+            process: Arc::downgrade(&process),
+            status: AsyncOnce::new(),
+        });
+        THREAD_EXITS.lock().insert(thread_id, exit.clone());
+        process.add_thread(thread_id);
+
+        Self {
+            thread_id,
+            process,
+            page_table,
+            state,
+            exit,
+        }
+    }
+
+    /// Create a new empty process and an empty thread in that process. `parent_id` is the
+    /// process that creates it, if any.
+    pub async fn new_empty_process(
+        stack_size: usize,
+        parent_id: Option<usize>,
+    ) -> Result<Self, &'static str> {
+        let process = Process::new(parent_id, |process_id| {
+            let page_table = Self::create_new_kernel_only_pagetable_from_current();
+            Ok(KernelPageTable::new(page_table, process_id))
+        })?;
+        let page_table = process.page_table().unwrap();
+        let mut thread = Self::new(
+            process,
+            page_table,
+            ThreadState::NotStarted(Self::initial_registers()),
+        );
+        thread.setup_user_stack(stack_size).await?;
         thread
             .increase_user_heap(globals::USER_HEAP_DEFAULT_SIZE)
-            .await
-            .unwrap();
-        thread
+            .await?;
+        Ok(thread)
     }
 
     /// Create a new thread in the current address space.
-    pub async fn new_empty_thread(&self, stack_size: usize) -> Self {
-        let mut thread = Self {
-            thread_id: THREAD_ID_GENERATOR.get_value(),
-            page_table: self.page_table.clone(),
-            state: ThreadState::NotStarted(Registers::default()),
-        };
-        thread.setup_user_stack(stack_size).await;
+    pub async fn new_empty_thread(&self, stack_size: usize) -> Result<Self, &'static str> {
+        let mut thread = Self::new(
+            self.process.clone(),
+            self.page_table.clone(),
+            ThreadState::NotStarted(Self::initial_registers()),
+        );
+        thread.setup_user_stack(stack_size).await?;
         thread
             .increase_user_heap(globals::USER_HEAP_DEFAULT_SIZE)
-            .await
-            .unwrap();
-        thread
+            .await?;
+        Ok(thread)
+    }
+
+    /// Create a copy of the process of this thread that only contains a copy of this thread.
+    /// The memory is shared copy-on-write. The new thread continues from the current syscall
+    /// and sees a return value of 0.
+    pub async fn fork(&self) -> Result<Self, &'static str> {
+        let (mut registers, fpu) = match &self.state {
+            ThreadState::Syscall(state) => (state.registers.clone(), state.fpu.clone()),
+            _ => return Err("Fork is only possible from a syscall"),
+        };
+        registers.set_syscall_result(Ok((0, 0)));
+
+        let mut kpt = self.page_table.lock().await;
+        let child = Process::new(Some(self.process.process_id()), |process_id| {
+            kpt.fork(Self::create_new_kernel_only_pagetable_from_current(), process_id)
+        })?;
+        let child_kpt = child.page_table().unwrap();
+        self.process.insert_handle(Handle::Process(child.clone()));
+
+        // The child resumes right after the syscall, the same way as a preempted thread.
+        let state = ThreadState::Preempted(registers, fpu);
+        Ok(Self::new(child, child_kpt, state))
+    }
+
+    /// Wait until the thread with `thread_id` finishes and get its exit status. Only threads
+    /// of the same process can be joined and each thread can only be joined once.
+    pub async fn join(&self, thread_id: usize) -> Result<ExitStatus, &'static str> {
+        if thread_id == self.thread_id {
+            return Err("A thread cannot join itself");
+        }
+
+        let exit = {
+            let exits = THREAD_EXITS.lock();
+            let exit = exits.get(&thread_id).ok_or("Thread does not exist")?;
+            if exit.process.as_ptr() != Arc::as_ptr(&self.process) {
+                return Err("Thread belongs to a different process");
+            }
+            exit.clone()
+        };
+
+        let status = *exit.status.clone().await;
+
+        // Another thread could have joined in the meantime and the id reused.
+        let mut exits = THREAD_EXITS.lock();
+        if matches!(exits.get(&thread_id), Some(e) if Arc::ptr_eq(e, &exit)) {
+            exits.remove(&thread_id);
+        }
+        Ok(status)
     }
 
     /// Run the thread until its end. This is an async method that will yield
     /// when the thread calls into kernel or is preempted.
-    pub async fn run_thread(mut self) -> u8 {
+    /// The exit status is also made available to the threads joining this thread
+    /// and to the process.
+    pub async fn run_thread(mut self) -> ExitStatus {
+        let status = self.run_until_exit().await;
+        self.exit.status.try_set_result(status);
+        self.process.remove_thread(self.thread_id, Some(status));
+        status
+    }
+
+    async fn run_until_exit(&mut self) -> ExitStatus {
+        // Page faults in a row that could not be resolved because there was no memory.
+        let mut out_of_memory_faults = 0;
         loop {
+            if let Some(status) = self.process.killed() {
+                info!(
+                    target: "thread",
+                    "Thread with id {} terminated because its process was {}",
+                    self.thread_id, status
+                );
+                return status;
+            }
             self.activate().await;
-            super::user_future::user_switching_fn(&mut self);
+            super::user_future::user_switching_fn(self);
 
             match self.state {
                 ThreadState::Running => panic!("Thread cannot be in Running state after running!"),
                 ThreadState::NotStarted(_) => panic!("Thread cannot be NotStarted after running!"),
-                ThreadState::Syscall(_) => {
-                    if let Poll::Ready(ret_val) = self.process_syscall().await {
-                        return ret_val;
+                ThreadState::Syscall(ref state) => {
+                    // A syscall that only waits is cancelled when the process is killed. The
+                    // thread exits at the start of the next iteration then.
+                    let result = if state.only_waits() {
+                        let process = self.process.clone();
+                        process.unless_killed(self.process_syscall()).await
+                    } else {
+                        Some(self.process_syscall().await)
+                    };
+                    if let Some(Poll::Ready(ret_val)) = result {
+                        return ExitStatus::Exited(ret_val);
+                    }
+                }
+                ThreadState::Preempted(..) => {
+                    // The scheduler might not go idle while user threads are busy. So, the
+                    // timers are also checked on every time slice.
+                    crate::common::time::wake_expired_timers();
+
+                    // Let the other tasks on the executor run before resuming.
+                    futures_lite::future::yield_now().await;
+                }
+                ThreadState::Faulted(_, _, fault) => {
+                    let result = {
+                        let mut kpt = self.page_table.lock().await;
+                        kpt.handle_page_fault(fault.address, fault.error_code)
+                    };
+
+                    match result {
+                        Ok(()) => out_of_memory_faults = 0,
+                        Err(PageFaultError::StackOverflow(thread_id)) => {
+                            info!(
+                                target: "thread",
+                                "Thread with id {} terminated due to a stack overflow in thread {} at {:#x} (ip: {:#x})",
+                                self.thread_id, thread_id, fault.address, fault.ip
+                            );
+                            return ExitStatus::StackOverflow { thread_id, fault };
+                        }
+                        Err(PageFaultError::Invalid(reason)) => {
+                            info!(
+                                target: "thread",
+                                "Thread with id {} terminated due to a page fault at {:#x} (ip: {:#x}): {}",
+                                self.thread_id, fault.address, fault.ip, reason
+                            );
+                            return ExitStatus::PageFault(fault);
+                        }
+                        Err(PageFaultError::OutOfMemory) => {
+                            out_of_memory_faults += 1;
+                            if out_of_memory_faults > oom::MAX_RETRIES {
+                                info!(
+                                    target: "thread",
+                                    "Thread with id {} terminated because killing processes did not free memory for it",
+                                    self.thread_id
+                                );
+                                return ExitStatus::OutOfMemory;
+                            }
+                            let victim = match oom::kill_largest().await {
+                                Some(victim) => victim,
+                                None => return ExitStatus::OutOfMemory,
+                            };
+
+                            // Wait for the memory to be freed. The thread faults again if
+                            // there is still no memory or exits if its process was killed.
+                            if !Arc::ptr_eq(&victim, &self.process) {
+                                self.process.unless_killed(victim.wait()).await;
+                            }
+                            futures_lite::future::yield_now().await;
+                        }
+                    }
+
+                    // Resume the thread the same way as a preempted one. It faults again if the
+                    // fault could not be resolved.
+                    let state = core::mem::replace(&mut self.state, ThreadState::Running);
+                    if let ThreadState::Faulted(registers, fpu, _) = state {
+                        self.state = ThreadState::Preempted(registers, fpu);
                     }
                 }
             }
         }
     }
 
+    /// Registers for a thread that has not started yet. Interrupts are enabled
+    /// so that the thread can be preempted by the timer.
+    fn initial_registers() -> Registers {
+        let mut registers = Registers::default();
+        registers.rflags = RFlags::INTERRUPT_FLAG.bits();
+        registers
+    }
+
     /// Set the initial user IP. This is only callable when creating a thread.
     pub fn setup_user_ip(&mut self, ip: u64) {
         if let ThreadState::NotStarted(registers) = &mut self.state {
@@ -90,28 +289,49 @@ impl Thread {
         }
     }
 
-    async fn setup_user_stack(&mut self, stack_size: usize) {
+    /// Get the initial user stack pointer. This is only callable when creating a thread.
+    pub fn user_stack_pointer(&self) -> u64 {
+        if let ThreadState::NotStarted(registers) = &self.state {
+            registers.rsp
+        } else {
+            panic!("Cannot get stack pointer when threadstate is not in NotStarted state.")
+        }
+    }
+
+    /// Set the initial user stack pointer. This is only callable when creating a thread.
+    pub fn setup_user_stack_pointer(&mut self, rsp: u64) {
+        if let ThreadState::NotStarted(registers) = &mut self.state {
+            registers.rsp = rsp;
+        } else {
+            panic!("Cannot setup stack pointer when threadstate is not in NotStarted state.")
+        }
+    }
+
+    async fn setup_user_stack(&mut self, stack_size: usize) -> Result<(), &'static str> {
+        // Stacks reserve a large area and grow into it on demand.
+        let stack_size = max(stack_size, globals::USER_STACK_MIN_RESERVE);
+        if stack_size > globals::USER_STACK_END - globals::USER_MMAP_END {
+            return Err("Stack size is too large");
+        }
         let stack_size = align_up(stack_size, globals::PAGE_SIZE);
 
-        // TODO: Make sure user_stack_allocated_until doesn't fall below a predetermined limit.
+        // Stacks are allocated downwards and must not run into the mappings.
         let mut kpt = self.page_table.lock().await;
-        let current_stack_end = kpt.user_stack_allocated_until;
-        let stack_start = current_stack_end - stack_size + 1;
-        // Leave 2 page size as guard page.
-        kpt.user_stack_allocated_until = current_stack_end - stack_size - (2 * globals::PAGE_SIZE);
-
-        kpt.map_with_alloc(
-            stack_start as *const u8,
-            stack_size,
-            MapperPermissions::READ | MapperPermissions::RING_3 | MapperPermissions::WRITE,
-        )
-        .unwrap();
+        let stack_end = kpt.user_stack_allocated_until + 1;
+        let guard_start = stack_end
+            .checked_sub(stack_size + globals::USER_STACK_GUARD_SIZE)
+            .filter(|start| *start > globals::USER_MMAP_END)
+            .ok_or("Out of user stack space")?;
+        kpt.reserve_stack(stack_end as u64, stack_size, self.thread_id)?;
+        kpt.user_stack_allocated_until = guard_start - 1;
+
         if let ThreadState::NotStarted(registers) = &mut self.state {
-            registers.rbp = stack_start as u64 + stack_size as u64;
-            registers.rsp = stack_start as u64 + stack_size as u64;
+            registers.rbp = stack_end as u64;
+            registers.rsp = stack_end as u64;
         } else {
             panic!("Cannot setup user stack when threadstate is not in syscall.")
         }
+        Ok(())
     }
 
     async fn increase_user_heap(
@@ -126,6 +346,10 @@ impl Thread {
         &self.page_table
     }
 
+    pub fn process(&self) -> &Arc<Process> {
+        &self.process
+    }
+
     /// Activate the current thread.
     pub async fn activate(&mut self) {
         let mut pt = self.page_table.lock().await;
@@ -152,6 +376,10 @@ impl Thread {
 
 impl Drop for Thread {
     fn drop(&mut self) {
+        // Threads that never ran are removed here. Joining threads see them as killed. The
+        // status of threads that ran to their end is already set and stays.
+        self.exit.status.try_set_result(ExitStatus::Killed);
+        self.process.remove_thread(self.thread_id, None);
         THREAD_ID_GENERATOR.return_value(self.thread_id);
     }
 }
@@ -1,17 +1,16 @@
 //! Stitching logic for user stacks on to kernel stacks.
 //! This switcher switches to a user stack and executes until a syscall or preemption. Once either is
-//! encountered, it stitches back the syscall request into the kernel stack making the kernel think that
-//! the current async task has returned with a syscall request. This allows the kernel to operate on a
+//! encountered, it stitches back the syscall request (or the preempted state) into the kernel stack making
+//! the kernel think that the current async task has returned with a syscall request. This allows the kernel to operate on a
 //! single thread while the user thread has its own stack.
 
-use moondust_sys::syscall::SyscallWrapper;
 use x86_64::{registers::model_specific::LStar, VirtAddr};
 
 use super::{
-    state::{Registers, SyscallState, ThreadState},
+    state::{FpuState, PageFault, Registers, SyscallState, ThreadState},
     Thread,
 };
-use crate::arch::cpu_locals;
+use crate::arch::{cpu_locals, gdt, interrupts::context::InterruptedContext};
 use moondust_utils::sync::once::AsyncOnce;
 
 /// The function that implements the switching logic.
@@ -52,17 +51,30 @@ pub fn user_switching_fn<'a>(thread: &'a mut Thread) {
                 "[CPU:{}][Thread:{}] Thread state was not started. Starting now.",
                 cpu_locals::PROCESSOR_ID.get(),
                 thread_id);
+            INITIAL_FPU_STATE.restore();
+            // The offsets below follow the layout of [`Registers`]. sysret loads rip from rcx
+            // and rflags from r11.
             unsafe {
                 asm!("
                         cli
-                        mov rsp, rdx
-                        mov rbp, rsi
+                        mov rcx, [rax + 128]
+                        mov r11, [rax + 136]
+                        mov rdx, [rax + 8]
+                        mov rsi, [rax + 16]
+                        mov rdi, [rax + 24]
+                        mov rbx, [rax + 40]
+                        mov r8, [rax + 48]
+                        mov r9, [rax + 56]
+                        mov r10, [rax + 64]
+                        mov r12, [rax + 80]
+                        mov r13, [rax + 88]
+                        mov r14, [rax + 96]
+                        mov r15, [rax + 104]
+                        mov rbp, [rax + 120]
+                        mov rsp, [rax + 112]
+                        mov rax, [rax + 32]
                         sysretq
-                    ", in("rdi") registers.rdi, in("rsi") registers.rbp, in("rax") registers.rax,
-                    in("rbx") registers.rbx, in("rcx") registers.rip, in("rdx") registers.rsp,
-                    in("r8") registers.r8, in("r9") registers.r9, in("r10") registers.r10,
-                    in("r11") registers.rflags, in("r12") registers.r12, in("r13") registers.r13,
-                    in("r14") registers.r14, in("r15") registers.r15);
+                    ", in("rax") registers as *const Registers);
             }
         }
         ThreadState::Syscall(state) => {
@@ -78,49 +90,123 @@ pub fn user_switching_fn<'a>(thread: &'a mut Thread) {
                 "[CPU:{}][Thread:{}] Thread state was syscall and returning to user.",
                 cpu_locals::PROCESSOR_ID.get(),
                 thread_id);
+            state.fpu.restore();
             let registers = &mut state.registers;
+            // The offsets below follow the layout of [`Registers`]. sysret loads rip from rcx
+            // and rflags from r11.
             unsafe {
                 asm!("
                         cli
-                        mov rsp, rdx
-                        mov rbp, rsi
+                        mov rcx, [rax + 128]
+                        mov r11, [rax + 136]
+                        mov rdx, [rax + 8]
+                        mov rsi, [rax + 16]
+                        mov rdi, [rax + 24]
+                        mov rbx, [rax + 40]
+                        mov r8, [rax + 48]
+                        mov r9, [rax + 56]
+                        mov r10, [rax + 64]
+                        mov r12, [rax + 80]
+                        mov r13, [rax + 88]
+                        mov r14, [rax + 96]
+                        mov r15, [rax + 104]
+                        mov rbp, [rax + 120]
+                        mov rsp, [rax + 112]
+                        mov rax, [rax + 32]
                         sysretq
-                    ", in("rdi") registers.rdi, in("rsi") registers.rbp, in("rax") registers.rax,
-                    in("rbx") registers.rbx, in("rcx") registers.rip, in("rdx") registers.rsp,
-                    in("r8") registers.r8, in("r9") registers.r9, in("r10") registers.r10,
-                    in("r11") registers.rflags, in("r12") registers.r12, in("r13") registers.r13,
-                    in("r14") registers.r14, in("r15") registers.r15);
+                    ", in("rax") registers as *const Registers);
+            }
+        }
+        ThreadState::Faulted(..) => {
+            panic!("Thread cannot be resumed before the fault is resolved!");
+        }
+        ThreadState::Preempted(registers, fpu) => {
+            debug!(target: "user_future",
+                "[CPU:{}][Thread:{}] Thread state was preempted and returning to user.",
+                cpu_locals::PROCESSOR_ID.get(),
+                thread_id);
+
+            // sysret clobbers rcx and r11. So, we use iret to restore all the registers.
+            // The offsets below follow the layout of [`Registers`].
+            fpu.restore();
+            let (user_cs, user_ss) = gdt::get_user_selectors();
+            unsafe {
+                asm!("
+                        cli
+                        push rsi
+                        push qword ptr [rax + 112]
+                        push qword ptr [rax + 136]
+                        push rdi
+                        push qword ptr [rax + 128]
+
+                        mov rcx, [rax]
+                        mov rdx, [rax + 8]
+                        mov rsi, [rax + 16]
+                        mov rdi, [rax + 24]
+                        mov rbx, [rax + 40]
+                        mov r8, [rax + 48]
+                        mov r9, [rax + 56]
+                        mov r10, [rax + 64]
+                        mov r11, [rax + 72]
+                        mov r12, [rax + 80]
+                        mov r13, [rax + 88]
+                        mov r14, [rax + 96]
+                        mov r15, [rax + 104]
+                        mov rbp, [rax + 120]
+                        mov rax, [rax + 32]
+                        iretq
+                    ", in("rax") registers as *const Registers, in("rdi") user_cs.0 as u64,
+                    in("rsi") user_ss.0 as u64);
             }
         }
     }
 
-    // The following body is directly invoked from [`syscall_entry_fn_2`].
+    // The following body is directly invoked from [`syscall_entry_fn_2`] or [`preempt_user_thread`].
     let regs: Registers;
     let syscall_state: SyscallState;
     unsafe {
-        let retrieved_syscall: *mut SyscallWrapper;
+        let is_syscall: u64;
         asm!(
             "user_future_resume_point:
             nop
             sti
             ",
             out("rax") _, out("rbx") _, out("rcx") _, out("rdx") _, out("rsi") _,
-            out("rdi") retrieved_syscall, out("r8") _, out("r9") _, out("r10") _, out("r11") _, out("r12") _,
+            out("rdi") is_syscall, out("r8") _, out("r9") _, out("r10") _, out("r11") _, out("r12") _,
             out("r13") _, out("r14") _, out("r15") _,
         );
         regs = REGISTERS.take().expect("Expected REGISTERS after sysret");
+        // The kernel doesn't use the FPU. So, the registers still belong to the thread. They
+        // are saved for syscalls as well because other threads run on the core in the meantime.
+        let fpu = FpuState::save();
+
+        // If there is no syscall, the thread either faulted or was preempted.
+        if is_syscall == 0 {
+            if let Some(fault) = USER_FAULT.take() {
+                debug!(target: "user_future",
+                    "[CPU:{}][Thread:{}] Thread returned from usermode by a page fault.",
+                    cpu_locals::PROCESSOR_ID.get(),
+                    thread_id);
+                thread.state = ThreadState::Faulted(regs, fpu, fault);
+                return;
+            }
+
+            debug!(target: "user_future",
+                "[CPU:{}][Thread:{}] Thread returned from usermode by preemption.",
+                cpu_locals::PROCESSOR_ID.get(),
+                thread_id);
+            thread.state = ThreadState::Preempted(regs, fpu);
+            return;
+        }
+
         debug!(target: "user_future",
             "[CPU:{}][Thread:{}] Thread returned from usermode by making a syscall.",
             cpu_locals::PROCESSOR_ID.get(),
             thread_id);
         syscall_state = SyscallState {
             registers: regs,
-            syscall_info: (*retrieved_syscall)
-                .call_info
-                .take()
-                .expect("Did not get a syscall from user"),
+            fpu,
             return_data_awaiter: AsyncOnce::new(),
-            return_data: &mut (*retrieved_syscall).return_info,
         };
     }
 
@@ -128,7 +214,70 @@ pub fn user_switching_fn<'a>(thread: &'a mut Thread) {
     return;
 }
 
+/// Preempt the user thread running on the current CPU. The registers of the thread are stored
+/// and the execution is stitched back to the kernel stack in [`user_switching_fn`].
+/// This is called from the timer interrupt when it interrupts ring 3.
+pub fn preempt_user_thread(context: &InterruptedContext) -> ! {
+    unsafe {
+        REGISTERS = Some(registers_from_context(context));
+        jump_to_resume_point(false);
+    }
+}
+
+/// Stop the user thread running on the current CPU because of a page fault. The registers
+/// are stored so that the thread can be resumed if the kernel can resolve the fault.
+/// The execution is stitched back to the kernel stack in [`user_switching_fn`].
+pub fn fault_user_thread(context: &InterruptedContext, fault: PageFault) -> ! {
+    unsafe {
+        REGISTERS = Some(registers_from_context(context));
+        USER_FAULT = Some(fault);
+        jump_to_resume_point(false);
+    }
+}
+
+fn registers_from_context(context: &InterruptedContext) -> Registers {
+    let mut regs = Registers::new();
+    regs.rax = context.rax;
+    regs.rbx = context.rbx;
+    regs.rcx = context.rcx;
+    regs.rdx = context.rdx;
+    regs.rsi = context.rsi;
+    regs.rdi = context.rdi;
+    regs.r8 = context.r8;
+    regs.r9 = context.r9;
+    regs.r10 = context.r10;
+    regs.r11 = context.r11;
+    regs.r12 = context.r12;
+    regs.r13 = context.r13;
+    regs.r14 = context.r14;
+    regs.r15 = context.r15;
+    regs.rsp = context.rsp;
+    regs.rbp = context.rbp;
+    regs.rip = context.rip;
+    regs.rflags = context.rflags;
+    regs
+}
+
+/// Restore the kernel stack stored in [`TRAMPOLINE_1_RSP_RBP`] and continue at the resume
+/// point in [`user_switching_fn`]. `is_syscall` tells if the thread stopped with a syscall.
+unsafe fn jump_to_resume_point(is_syscall: bool) -> ! {
+    unsafe {
+        let (rsp, rbp) = TRAMPOLINE_1_RSP_RBP;
+        asm!(
+            "
+            mov rbp, {1}
+            mov rsp, {0}
+            jmp user_future_resume_point
+        ", in(reg) rsp, in(reg) rbp, in("rdi") is_syscall as u64, options(noreturn));
+    }
+}
+
+/// FPU state of the threads that start. Also hides the state of the previous thread on the core.
+static INITIAL_FPU_STATE: FpuState = FpuState::initial();
+
 // We store RSP and RBP so that the user stack can be stitched back to the kernel stack.
+// This is per core. A user thread always stops on the core that switched to it in the same
+// poll of its task. So, the task can be polled on a different core the next time.
 #[thread_local]
 static mut TRAMPOLINE_1_RSP_RBP: (u64, u64) = (0, 0);
 
@@ -137,66 +286,63 @@ fn set_syscall_location(syscall_entry: *const ()) {
     LStar::write(VirtAddr::new(syscall_entry as u64));
 }
 
-// Syscall: rcx -> rdi (IP) ... rdi -> info
+/// Entry point of the `syscall` instruction. The CPU stores the user rip in rcx and the
+/// rflags in r11. The user stack is not touched in ring 0: the entry switches to the syscall
+/// stack of the core in [`gdt::SyscallStacks`] and pushes all the registers there as
+/// [`Registers`]. Interrupts stay disabled until the resume point.
 #[inline(never)]
 #[naked]
-unsafe extern "C" fn syscall_entry_fn(
-    _info: *const SyscallWrapper,
-    _b: u64,
-    _c: u64,
-    _stored_ip: u64,
-) {
-    // naked to retrieve the values and not corrupt stack. We want to read the stack information here.
+unsafe extern "C" fn syscall_entry_fn() {
+    // naked to retrieve the values and not corrupt any register.
+    // The kernel GS base points to [`gdt::SyscallStacks`]. The offsets follow its layout.
+    // GS is swapped back before anything else runs so that it is only used here.
     unsafe {
         asm!("
-        mov rsi, rsp
-        mov rdx, rbp
-        jmp {0}
+        swapgs
+        mov gs:[8], rsp
+        mov rsp, gs:[0]
+
+        push r11
+        push rcx
+        push rbp
+        push qword ptr gs:[8]
+        swapgs
+
+        push r15
+        push r14
+        push r13
+        push r12
+        push r11
+        push r10
+        push r9
+        push r8
+        push rbx
+        push rax
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+
+        mov rdi, rsp
+        and rsp, -16
+        call {0}
     ", sym syscall_entry_fn_2, options(noreturn));
     }
 }
 
 /// This is used to store the register state and provide it back to the kernel stack.
+/// Like [`TRAMPOLINE_1_RSP_RBP`], this only lives until the resume point on the same core.
 #[thread_local]
 static mut REGISTERS: Option<Registers> = None;
 
-unsafe extern "C" fn syscall_entry_fn_2(
-    info: *const SyscallWrapper,
-    user_rsp: *const (),
-    user_rbp: *const (),
-    user_stored_ip: *const (),
-) {
-    // Once we store the stack, we capture the remaining registers so that we can restore them as needed
-    // at a later point in time.
-    unsafe {
-        let rbx: u64;
-        let r12: u64;
-        let r13: u64;
-        let r14: u64;
-        let r15: u64;
-        let rflags: u64;
-        asm!("nop", 
-            out("rbx") rbx, out("r12") r12, out("r13") r13,
-            out("r14") r14, out("r15") r15, out("r11") rflags);
-
-        let mut regs = Registers::new();
-        regs.rsp = user_rsp as u64;
-        regs.rbp = user_rbp as u64;
-        regs.rip = user_stored_ip as u64;
-        regs.rbx = rbx;
-        regs.r12 = r12;
-        regs.r13 = r13;
-        regs.r14 = r14;
-        regs.r15 = r15;
-        regs.rflags = rflags;
-        REGISTERS = Some(regs);
+/// This is used to provide the fault information back to the kernel stack.
+#[thread_local]
+static mut USER_FAULT: Option<PageFault> = None;
 
-        let (rsp, rbp) = TRAMPOLINE_1_RSP_RBP;
-        asm!(
-            "
-            mov rbp, {1}
-            mov rsp, {0}
-            jmp user_future_resume_point
-        ", in(reg) rsp, in(reg) rbp,  in("rdi") info);
+unsafe extern "C" fn syscall_entry_fn_2(registers: *const Registers) -> ! {
+    // The registers are copied out before the syscall stack is reused by the next syscall.
+    unsafe {
+        REGISTERS = Some((*registers).clone());
+        jump_to_resume_point(true);
     }
 }
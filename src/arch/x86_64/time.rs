@@ -0,0 +1,90 @@
+//! Time keeping for x86_64. The TSC and the LAPIC timer are calibrated against the PIT
+//! at boot. The TSC provides the monotonic clock.
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Length of the calibration in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+
+/// Frequency of the TSC in Hz.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Value of the TSC when the clock was calibrated. The monotonic clock starts here.
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Number of LAPIC timer ticks in a millisecond with the divider used by the kernel.
+static LAPIC_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Returns true if [`calibrate`] has been run.
+pub fn is_calibrated() -> bool {
+    LAPIC_TICKS_PER_MS.load(Ordering::SeqCst) != 0
+}
+
+/// Number of LAPIC timer ticks in a millisecond. See [`calibrate`].
+pub fn lapic_ticks_per_ms() -> u32 {
+    LAPIC_TICKS_PER_MS.load(Ordering::SeqCst)
+}
+
+/// Measure the frequency of the TSC and the LAPIC timer using the channel 2 of the PIT.
+/// `start_lapic_timer` must start the LAPIC timer as a masked one shot timer from `u32::MAX`
+/// and `read_lapic_timer` must read its current count.
+/// This is only run once on the BSP. The other cores use the same values.
+pub fn calibrate(start_lapic_timer: impl FnOnce(), read_lapic_timer: impl FnOnce() -> u32) {
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut data: Port<u8> = Port::new(0x42);
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    let (tsc_ticks, lapic_ticks) = unsafe {
+        // Enable the gate of channel 2 and keep the speaker off.
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+
+        // Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+        command.write(0b1011_0000);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        start_lapic_timer();
+        let tsc_start = _rdtsc();
+
+        // The output of channel 2 goes high when the count reaches 0.
+        while gate.read() & 0x20 == 0 {}
+
+        let lapic_end = read_lapic_timer();
+        let tsc_end = _rdtsc();
+        (tsc_end - tsc_start, u32::MAX - lapic_end)
+    };
+
+    let tsc_frequency = tsc_ticks * 1000 / CALIBRATION_MS;
+    let lapic_ticks_per_ms = (lapic_ticks as u64 / CALIBRATION_MS).max(1) as u32;
+    info!(
+        target: "time",
+        "TSC frequency: {} Hz, LAPIC timer ticks per ms: {}", tsc_frequency, lapic_ticks_per_ms
+    );
+
+    TSC_FREQUENCY.store(tsc_frequency, Ordering::SeqCst);
+    BOOT_TSC.store(unsafe { _rdtsc() }, Ordering::SeqCst);
+    LAPIC_TICKS_PER_MS.store(lapic_ticks_per_ms, Ordering::SeqCst);
+}
+
+/// Time since the clock was calibrated at boot. This never goes backwards.
+pub fn now() -> Duration {
+    let frequency = TSC_FREQUENCY.load(Ordering::SeqCst);
+    if frequency == 0 {
+        return Duration::from_secs(0);
+    }
+
+    let ticks = unsafe { _rdtsc() }.saturating_sub(BOOT_TSC.load(Ordering::SeqCst));
+    let nanos = ticks as u128 * 1_000_000_000 / frequency as u128;
+    Duration::from_nanos(nanos as u64)
+}
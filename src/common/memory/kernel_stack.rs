@@ -1,6 +1,6 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use super::paging::{IMemoryMapper, MapperPermissions};
+use super::paging::{IMemoryMapper, MapperHints, MapperPermissions};
 use crate::{arch::globals, common::align_down};
 
 static NUM_STACKS_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
@@ -16,6 +16,7 @@ pub fn create_new_kernel_stack(mapper: &mut dyn IMemoryMapper) -> *const () {
             start_addr as *const u8,
             globals::KERNEL_STACK_MAX_SIZE,
             MapperPermissions::WRITE | MapperPermissions::READ,
+            MapperHints::HUGE_2MIB,
         )
         .expect("Failed to create kernel stack");
 
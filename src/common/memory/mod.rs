@@ -2,3 +2,4 @@ pub mod cpu_local;
 pub mod fixed_size_block;
 pub mod kernel_stack;
 pub mod paging;
+pub mod user_ptr;
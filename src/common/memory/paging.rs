@@ -8,6 +8,7 @@ pub trait IMemoryMapper {
         virt_addr: *const u8,
         size: usize,
         permissions: MapperPermissions,
+        hints: MapperHints,
     ) -> Result<(), &'static str>;
 
     /// Map a virtual address and the given size.
@@ -16,9 +17,11 @@ pub trait IMemoryMapper {
         virt_addr: *const u8,
         size: usize,
         permissions: MapperPermissions,
+        hints: MapperHints,
     ) -> Result<(), &'static str>;
 
     /// Unmap a virtual address and return it's physical address and amount of data unmapped.
+    /// Huge pages that are only partly in the range are split first.
     fn unmap_range(&mut self, virt_addr: *const u8, size: usize) -> Result<(), &'static str>;
 
     /// Convert virtual address to physical address.
@@ -34,3 +37,13 @@ bitflags! {
         const RING_3    = 0b0000_1000;
     }
 }
+
+bitflags! {
+    /// Page sizes that a mapping is allowed to use. Huge pages are only used where the
+    /// addresses are aligned to the page size and the rest of the mapping is large enough.
+    /// Everything else is mapped with 4 KiB pages.
+    pub struct MapperHints : u8 {
+        const HUGE_2MIB = 0b0000_0001;
+        const HUGE_1GIB = 0b0000_0010;
+    }
+}
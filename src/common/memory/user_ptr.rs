@@ -0,0 +1,104 @@
+//! Pointers into user memory received from syscalls. The kernel never dereferences these
+//! directly. The range is validated against the address space of the process and the data is
+//! copied in and out through its page table.
+
+use core::{
+    marker::PhantomData,
+    mem::{size_of, MaybeUninit},
+    slice,
+};
+
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::arch::memory::kernel_page_table::KernelPageTable;
+
+/// A pointer to a `T` in user memory.
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    addr: u64,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> UserPtr<T> {
+    pub const fn new(addr: u64) -> Self {
+        Self {
+            addr,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The address in user memory.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Copy the value from user memory.
+    ///
+    /// # Safety
+    /// The user can write any bytes at the address. The caller must make sure that those
+    /// bytes are a valid `T`.
+    pub unsafe fn read(&self, kpt: &mut KernelPageTable) -> Result<T, &'static str> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let data =
+            unsafe { slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        kpt.copy_from_user(self.addr, data)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Copy `value` into user memory.
+    pub fn write(&self, kpt: &mut KernelPageTable, value: &T) -> Result<(), &'static str> {
+        kpt.write_to_user(self.addr, value)
+    }
+}
+
+/// A slice of `len` elements of `T` in user memory.
+#[derive(Debug)]
+pub struct UserSlice<T> {
+    addr: u64,
+    len: usize,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> UserSlice<T> {
+    pub const fn new(addr: u64, len: usize) -> Self {
+        Self {
+            addr,
+            len,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Size of the slice in bytes. Returns `None` if the size overflows.
+    fn size(&self) -> Option<usize> {
+        self.len.checked_mul(size_of::<T>())
+    }
+}
+
+impl UserSlice<u8> {
+    /// Copy the bytes from user memory.
+    pub fn read_to_vec(&self, kpt: &mut KernelPageTable) -> Result<Vec<u8>, &'static str> {
+        // Validate before allocating so that the user cannot make the kernel allocate an
+        // arbitrary amount of memory.
+        let size = self.size().ok_or("Invalid user address range")?;
+        if !kpt.is_valid_user_range(self.addr, size) {
+            return Err("Invalid user address range");
+        }
+
+        let mut data = vec![0; size];
+        kpt.copy_from_user(self.addr, &mut data)?;
+        Ok(data)
+    }
+
+    /// Copy an UTF-8 string from user memory.
+    pub fn read_to_string(&self, kpt: &mut KernelPageTable) -> Result<String, &'static str> {
+        String::from_utf8(self.read_to_vec(kpt)?).map_err(|_| "Invalid UTF-8 string")
+    }
+}
@@ -2,6 +2,7 @@ pub mod devices;
 pub mod memory;
 pub mod process;
 pub mod ramdisk;
+pub mod time;
 
 /// Align value upwards.
 ///
@@ -0,0 +1,136 @@
+//! Futexes let user threads block on a word in memory until another thread wakes them up.
+//! A futex is identified by the user address of the word in an address space. See
+//! [`crate::arch::memory::kernel_page_table::KernelPageTable::get_futex`].
+
+use core::{
+    fmt::Debug,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use alloc::sync::Arc;
+use futures_lite::Future;
+use moondust_utils::sync::waker_set::WakerSet;
+
+/// The threads waiting on a futex word.
+pub struct Futex {
+    wakers: WakerSet,
+    /// Number of threads waiting on the futex.
+    waiters: AtomicUsize,
+    /// Number of waiting threads that were woken up but haven't returned yet.
+    wakeups: AtomicUsize,
+}
+
+// WakerSet synchronizes the access to the wakers itself.
+unsafe impl Send for Futex {}
+unsafe impl Sync for Futex {}
+
+impl Debug for Futex {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Futex")
+            .field("waiters", &self.waiters.load(Ordering::SeqCst))
+            .field("wakeups", &self.wakeups.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+impl Futex {
+    pub const fn new() -> Self {
+        Self {
+            wakers: WakerSet::new(),
+            waiters: AtomicUsize::new(0),
+            wakeups: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of threads waiting on the futex.
+    pub fn waiters(&self) -> usize {
+        self.waiters.load(Ordering::SeqCst)
+    }
+
+    /// Register a waiter. The returned future completes when the waiter is woken up by
+    /// [`Futex::wake`]. A wake up after this call is never lost. So, the futex word must be
+    /// checked before calling this while holding the lock that [`Futex::wake`] is called with.
+    pub fn wait(self: &Arc<Self>) -> impl Future<Output = ()> {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        FutexWait {
+            futex: self.clone(),
+            opt_key: None,
+            completed: false,
+        }
+    }
+
+    /// Wake up to `count` waiters. Returns the number of waiters woken up.
+    pub fn wake(&self, count: usize) -> usize {
+        let waiting = self
+            .waiters
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.wakeups.load(Ordering::SeqCst));
+        let count = count.min(waiting);
+        self.wakeups.fetch_add(count, Ordering::SeqCst);
+        for _ in 0..count {
+            self.wakers.notify_one();
+        }
+        count
+    }
+
+    /// Take a pending wake up if there is one.
+    fn try_take_wakeup(&self) -> bool {
+        let taken = self
+            .wakeups
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |wakeups| {
+                wakeups.checked_sub(1)
+            })
+            .is_ok();
+        if taken {
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+        }
+        taken
+    }
+}
+
+struct FutexWait {
+    futex: Arc<Futex>,
+    opt_key: Option<usize>,
+    completed: bool,
+}
+
+impl Future for FutexWait {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // If the current task is in the set, remove it.
+        if let Some(key) = self.opt_key.take() {
+            self.futex.wakers.remove(key);
+        }
+
+        if !self.futex.try_take_wakeup() {
+            self.opt_key = Some(self.futex.wakers.insert(cx));
+
+            // The wake up might have happened before the waker was inserted.
+            if !self.futex.try_take_wakeup() {
+                return Poll::Pending;
+            }
+
+            if let Some(key) = self.opt_key.take() {
+                self.futex.wakers.remove(key);
+            }
+        }
+
+        self.completed = true;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for FutexWait {
+    fn drop(&mut self) {
+        // The wait was cancelled. For example, because the thread was killed.
+        if let Some(key) = self.opt_key {
+            self.futex.wakers.cancel(key);
+        }
+        if !self.completed {
+            self.futex.waiters.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! The init stage. Starts the programs listed in [`MANIFEST_PATH`] in the initrd and restarts
+//! them according to their restart policy.
+//!
+//! The manifest has one program per line. Empty lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! <path> [priority=<n>] [stack=<size>] [restart=never|on-failure|always] [-- <args>...]
+//! ```
+//!
+//! The size is in bytes and can end with `K` or `M`. By default, a program runs with priority
+//! [`DEFAULT_PRIORITY`], a stack of [`DEFAULT_STACK_SIZE`] bytes and is never restarted.
+
+use core::time::Duration;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    arch::process::state::ExitStatus,
+    common::{
+        process::spawn::{spawn_process, DEFAULT_STACK_SIZE},
+        ramdisk, time,
+    },
+    SCHEDULER, SCHEDULER_PRIORITIES,
+};
+
+/// Path of the manifest in the initrd.
+pub const MANIFEST_PATH: &str = "./sys/init.conf";
+
+/// Program that is started when there is no manifest.
+pub const DEFAULT_PROGRAM: &str = "./userspace/moondust-alpha";
+
+/// Priority of the programs that don't set one.
+pub const DEFAULT_PRIORITY: usize = 4;
+
+/// Time to wait before a program is restarted. This keeps a crashing program from taking
+/// all the time of the scheduler.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// When a program is started again after it finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    /// Restart unless the program exited with code 0.
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    /// Check if a program that finished with `status` should be started again.
+    pub fn should_restart(&self, status: &ExitStatus) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !matches!(status, ExitStatus::Exited(0)),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// A program listed in the manifest.
+#[derive(Debug, Clone)]
+pub struct InitEntry {
+    pub path: String,
+    pub args: Vec<String>,
+    pub priority: usize,
+    pub stack_size: usize,
+    pub restart: RestartPolicy,
+}
+
+impl InitEntry {
+    fn new(path: &str) -> Self {
+        Self {
+            path: path.into(),
+            args: Vec::new(),
+            priority: DEFAULT_PRIORITY,
+            stack_size: DEFAULT_STACK_SIZE,
+            restart: RestartPolicy::Never,
+        }
+    }
+
+    /// Parse a line of the manifest. Returns `None` for empty lines and comments.
+    pub fn parse(line: &str) -> Result<Option<Self>, &'static str> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let mut words = line.split_whitespace();
+        let mut entry = Self::new(words.next().unwrap());
+        while let Some(word) = words.next() {
+            if word == "--" {
+                entry.args.extend(words.map(String::from));
+                break;
+            }
+
+            match word.split_once('=') {
+                Some(("priority", value)) => {
+                    entry.priority = value.parse().map_err(|_| "Invalid priority")?;
+                    if entry.priority >= SCHEDULER_PRIORITIES {
+                        return Err("Priority is out of range");
+                    }
+                }
+                Some(("stack", value)) => entry.stack_size = parse_size(value)?,
+                Some(("restart", "never")) => entry.restart = RestartPolicy::Never,
+                Some(("restart", "on-failure")) => entry.restart = RestartPolicy::OnFailure,
+                Some(("restart", "always")) => entry.restart = RestartPolicy::Always,
+                Some(("restart", _)) => return Err("Unknown restart policy"),
+                _ => return Err("Unknown option"),
+            }
+        }
+
+        Ok(Some(entry))
+    }
+}
+
+/// Parse a size in bytes with an optional `K` or `M` suffix.
+fn parse_size(value: &str) -> Result<usize, &'static str> {
+    let (number, multiplier) = match value.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&value[..value.len() - 1], 1024),
+        Some(b'M') | Some(b'm') => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or("Invalid size")
+}
+
+/// Read the manifest from the initrd. Invalid lines are skipped. Falls back to
+/// [`DEFAULT_PROGRAM`] if there is no manifest.
+pub fn read_manifest() -> Vec<InitEntry> {
+    let initrd = ramdisk::initrd();
+    let manifest = match initrd.lookup(MANIFEST_PATH) {
+        Some(manifest) => manifest,
+        None => {
+            warn!(
+                target: "init",
+                "No {} in the initrd. Starting {}", MANIFEST_PATH, DEFAULT_PROGRAM
+            );
+            return vec![InitEntry::new(DEFAULT_PROGRAM)];
+        }
+    };
+
+    let manifest = match core::str::from_utf8(manifest) {
+        Ok(manifest) => manifest,
+        Err(_) => {
+            warn!(target: "init", "{} is not valid UTF-8", MANIFEST_PATH);
+            return Vec::new();
+        }
+    };
+
+    let mut entries = Vec::new();
+    for (number, line) in manifest.lines().enumerate() {
+        match InitEntry::parse(line) {
+            Ok(Some(entry)) => entries.push(entry),
+            Ok(None) => {}
+            Err(reason) => warn!(
+                target: "init",
+                "Skipping line {} of {}: {}", number + 1, MANIFEST_PATH, reason
+            ),
+        }
+    }
+    entries
+}
+
+/// Start all the programs in the manifest. Each program is supervised by its own task.
+pub async fn run() {
+    for entry in read_manifest() {
+        info!(target: "init", "Starting {:?}", entry);
+        SCHEDULER.spawn(2, supervise(entry)).detach();
+    }
+}
+
+/// Run the program and start it again whenever its restart policy asks for it.
+async fn supervise(entry: InitEntry) {
+    let args: Vec<&str> = entry.args.iter().map(String::as_str).collect();
+    loop {
+        let spawn = spawn_process(&entry.path, &args, &[], entry.stack_size, None);
+        let thread = match spawn.await {
+            Ok(thread) => thread,
+            Err(reason) => {
+                warn!(target: "init", "Cannot start {}: {}", entry.path, reason);
+                return;
+            }
+        };
+
+        // The program is done once all the threads of its process have finished.
+        let process = thread.process().clone();
+        SCHEDULER
+            .spawn(entry.priority, thread.run_thread())
+            .detach();
+        let status = process.wait().await;
+        info!(target: "init", "{} {}", entry.path, status);
+
+        if !entry.restart.should_restart(&status) {
+            return;
+        }
+        time::sleep(RESTART_DELAY).await;
+        info!(target: "init", "Restarting {}", entry.path);
+    }
+}
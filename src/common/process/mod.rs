@@ -1 +1,4 @@
+pub mod futex;
+pub mod init;
+pub mod spawn;
 pub mod syscall_process;
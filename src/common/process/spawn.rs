@@ -0,0 +1,157 @@
+//! Loading programs from the initial ram disk into new processes.
+
+use core::arch::x86_64::_rdtsc;
+
+use alloc::vec::Vec;
+use elfloader::ElfBinary;
+use moondust_sys::auxv;
+use x86_64::instructions::random::RdRand;
+
+use crate::{
+    arch::{globals, memory::kernel_page_table::KernelPageTable, process::Thread},
+    common::{
+        align_down,
+        ramdisk::{self, elf_loader::DefaultElfLoader},
+    },
+};
+
+/// Stack size of the main thread of a new process.
+pub const DEFAULT_STACK_SIZE: usize = 10 * 4096 * 1024;
+
+/// Create a new process that runs the program at `path` in the initrd.
+/// Returns the main thread of the process. It is not scheduled yet.
+/// The program gets `path` followed by `args` as its arguments and `env` as
+/// its `KEY=VALUE` variables. See [`moondust_sys::auxv`] for the initial stack.
+/// `parent_id` is the process that starts the program, if any.
+pub async fn spawn_process(
+    path: &str,
+    args: &[&str],
+    env: &[&str],
+    stack_size: usize,
+    parent_id: Option<usize>,
+) -> Result<Thread, &'static str> {
+    let mut thread = Thread::new_empty_process(stack_size, parent_id).await?;
+
+    let (entry_point, stack_pointer) = {
+        let mut kpt = thread.get_page_table().lock().await;
+        let program = load_program(&mut kpt, path)?;
+
+        let mut argv = Vec::with_capacity(args.len() + 1);
+        argv.push(path);
+        argv.extend_from_slice(args);
+        let auxiliary = [
+            (auxv::AT_PHDR, program.program_headers),
+            (auxv::AT_PHENT, program.program_header_size),
+            (auxv::AT_PHNUM, program.program_header_count),
+            (auxv::AT_PAGESZ, globals::PAGE_SIZE as u64),
+            (auxv::AT_ENTRY, program.entry_point),
+        ];
+        let stack_pointer =
+            setup_initial_stack(&mut kpt, thread.user_stack_pointer(), &argv, env, &auxiliary)?;
+        (program.entry_point, stack_pointer)
+    };
+
+    thread.setup_user_ip(entry_point);
+    thread.setup_user_stack_pointer(stack_pointer);
+    thread.setup_user_custom_data(stack_pointer);
+    Ok(thread)
+}
+
+/// Addresses of a program loaded by [`load_program`].
+struct LoadedProgram {
+    entry_point: u64,
+    program_headers: u64,
+    program_header_size: u64,
+    program_header_count: u64,
+}
+
+/// Load the ELF binary at `path` in the initrd into the page table.
+fn load_program(kpt: &mut KernelPageTable, path: &str) -> Result<LoadedProgram, &'static str> {
+    let ramdisk = ramdisk::initrd();
+    let file = ramdisk.lookup(path).ok_or("Program not found")?;
+    let binary = ElfBinary::new(path, file)?;
+
+    let mut loader = DefaultElfLoader::new(0x0, kpt);
+    binary.load(&mut loader)?;
+    info!(target: "spawn",
+        "{} loaded. Use command `add-symbol-file <binary> 0x{:x}` to debug it",
+        path,
+        loader.get_exe_location());
+
+    let header = &binary.file.header.pt2;
+    Ok(LoadedProgram {
+        entry_point: binary.entry_point(),
+        program_headers: loader
+            .file_offset_to_vaddr(header.ph_offset())
+            .unwrap_or(0),
+        program_header_size: header.ph_entry_size() as u64,
+        program_header_count: header.ph_count() as u64,
+    })
+}
+
+/// Write the arguments, the variables and the auxiliary vector below `stack_top` as described
+/// in [`moondust_sys::auxv`]. Returns the new stack pointer.
+fn setup_initial_stack(
+    kpt: &mut KernelPageTable,
+    stack_top: u64,
+    args: &[&str],
+    env: &[&str],
+    auxiliary: &[(u64, u64)],
+) -> Result<u64, &'static str> {
+    let mut stack_pointer = stack_top;
+
+    stack_pointer -= 16;
+    kpt.copy_to_user(stack_pointer, &random_bytes())?;
+    let random = stack_pointer;
+
+    let mut push_string = |string: &str| -> Result<u64, &'static str> {
+        stack_pointer -= string.len() as u64 + 1;
+        kpt.copy_to_user(stack_pointer, string.as_bytes())?;
+        kpt.copy_to_user(stack_pointer + string.len() as u64, &[0u8])?;
+        Ok(stack_pointer)
+    };
+    let arg_pointers = args
+        .iter()
+        .map(|arg| push_string(arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let env_pointers = env
+        .iter()
+        .map(|var| push_string(var))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut words = Vec::with_capacity(args.len() + env.len() + 2 * auxiliary.len() + 5);
+    words.push(args.len() as u64);
+    words.extend(arg_pointers);
+    words.push(0);
+    words.extend(env_pointers);
+    words.push(0);
+    let end = [(auxv::AT_RANDOM, random), (auxv::AT_NULL, 0)];
+    for (key, value) in auxiliary.iter().chain(&end) {
+        words.push(*key);
+        words.push(*value);
+    }
+
+    let mut data = Vec::with_capacity(words.len() * 8);
+    for word in words {
+        data.extend_from_slice(&word.to_ne_bytes());
+    }
+    stack_pointer = align_down(stack_pointer as usize - data.len(), 16) as u64;
+    kpt.copy_to_user(stack_pointer, &data)?;
+    Ok(stack_pointer)
+}
+
+/// Random bytes for [`auxv::AT_RANDOM`]. The time stamp counter is used if the CPU cannot
+/// generate random numbers.
+fn random_bytes() -> [u8; 16] {
+    let rdrand = RdRand::new();
+    let next = || {
+        rdrand
+            .and_then(|rdrand| rdrand.get_u64())
+            .unwrap_or_else(|| unsafe { _rdtsc() }.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+    };
+
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&next().to_ne_bytes());
+    bytes[8..].copy_from_slice(&next().to_ne_bytes());
+    bytes
+}
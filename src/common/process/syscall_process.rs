@@ -1,14 +1,29 @@
 //! Syscall processing logic for threads.
 
-use core::{panic, task::Poll};
+use core::{panic, task::Poll, time::Duration};
 
-use moondust_sys::syscall::{HeapControl, ProcessControl, Syscalls, Sysrets};
+use moondust_sys::syscall::{
+    numbers, protection, ExitKind, ExitReason, SyscallError, SyscallResult, SYSCALL_ABI_VERSION,
+};
 
-use crate::arch::process::{
-    state::{SyscallState, ThreadState},
-    Thread,
+use crate::{
+    arch::{
+        memory::{frame_allocator, kernel_page_table},
+        process::{
+            process_table::{self, Handle},
+            state::{ExitStatus, SyscallState, ThreadState},
+            Thread,
+        },
+    },
+    common::{
+        memory::{paging::MapperPermissions, user_ptr::UserSlice},
+        process::spawn::{spawn_process, DEFAULT_STACK_SIZE},
+        time,
+    },
 };
 
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
 impl Thread {
     /// Process the syscall requested by a thread.
     /// A return of [Poll::Pending] means that the syscall
@@ -16,82 +31,240 @@ impl Thread {
     /// In such a case, the waker is used to reschedule the task
     /// when appropriate.
     /// Return of a [Poll::Ready] means the thread exited.
+    /// All the data from the user is validated and copied in before it is used.
+    /// See [`moondust_sys::syscall`] for the numbers and the arguments.
     /// IMPORTANT: Any code after await in this function can switch
     /// to a different address space.
     pub async fn process_syscall(&mut self) -> Poll<u8> {
-        let state = &mut self.state;
-        let syscall = match state {
-            ThreadState::Syscall(a) => a,
-            _ => panic!("Cannot process syscall when not in Syscall state"),
-        };
-        // TODO: sanitize/verify
-        match syscall.syscall_info {
-            Syscalls::Exit(val) => {
+        let syscall = self.get_syscall();
+        let number = syscall.number();
+        let args = syscall.args();
+
+        let result: SyscallResult = match number {
+            numbers::ABI_VERSION => Ok((SYSCALL_ABI_VERSION, 0)),
+            numbers::EXIT => {
+                let val = args[0] as u8;
                 info!("Thread with id {} exited with code {}", self.thread_id, val);
-                Poll::Ready(val)
-            }
-            Syscalls::Debug { data } => {
-                info!("Test syscall with val {}", data);
-                *syscall.return_data = Sysrets::NoVal;
-                syscall.return_data_awaiter.try_set_result(());
-                Poll::Pending
+                return Poll::Ready(val);
             }
-            Syscalls::Heap(ref heap_control) => match heap_control {
-                HeapControl::GetCurrentHeapSize => {
-                    let heap_size: usize;
-                    {
-                        let kpt = self.get_page_table().lock().await;
-                        heap_size = kpt.get_user_heap_size();
+            numbers::DEBUG => {
+                let data = UserSlice::<u8>::new(args[0], args[1] as usize);
+                let mut kpt = self.get_page_table().lock().await;
+                match data.read_to_string(&mut kpt) {
+                    Ok(data) => {
+                        info!("Test syscall with val {}", data);
+                        Ok((0, 0))
                     }
+                    Err(_) => Err(SyscallError::InvalidArgument),
+                }
+            }
+            numbers::HEAP_SIZE => {
+                let kpt = self.get_page_table().lock().await;
+                Ok((kpt.get_user_heap_size() as _, 0))
+            }
+            numbers::HEAP_INCREASE => {
+                let mut kpt = self.get_page_table().lock().await;
+                match kpt.map_more_user_heap(args[0] as usize) {
+                    Ok((a, b)) => Ok((a as _, b as _)),
+                    Err(kernel_page_table::INVALID_SIZE) => Err(SyscallError::InvalidArgument),
+                    Err(_) => Err(SyscallError::Failed),
+                }
+            }
+            // sysret faults in the kernel for non canonical addresses. So, only user addresses
+            // are allowed for the entry point.
+            numbers::CREATE_THREAD if !crate::arch::is_user_address(args[0]) => {
+                Err(SyscallError::InvalidArgument)
+            }
+            numbers::CREATE_THREAD => match self.new_empty_thread(args[1] as usize).await {
+                Ok(mut thread) => {
+                    thread.setup_user_ip(args[0]);
+                    thread.setup_user_custom_data(args[2]);
 
-                    let syscall = self.get_syscall();
-                    *syscall.return_data = Sysrets::SuccessWithVal(heap_size as _);
-                    syscall.return_data_awaiter.try_set_result(());
-                    Poll::Pending
-                }
-                HeapControl::IncreaseHeapBy(size) => {
-                    let sysret = {
-                        let size_to_increase = *size;
-                        let mut kpt = self.get_page_table().lock().await;
-                        match kpt.map_more_user_heap(size_to_increase) {
-                            Ok((a, b)) => Sysrets::SuccessWithVal2(a as _, b as _),
-                            Err(_) => Sysrets::Fail,
-                        }
-                    };
-
-                    let syscall = self.get_syscall();
-                    *syscall.return_data = sysret;
-                    syscall.return_data_awaiter.try_set_result(());
-                    Poll::Pending
+                    let thread_id = thread.thread_id;
+                    crate::SCHEDULER
+                        .spawn(2, crate::SPAWN_THREADS.get().unwrap().send((thread, 1)))
+                        .detach();
+                    Ok((thread_id as u64, 0))
+                }
+                Err(reason) => {
+                    warn!("Cannot create a thread for thread {}: {}", self.thread_id, reason);
+                    Err(SyscallError::Failed)
                 }
             },
-            Syscalls::Process(ref process_control) => match process_control {
-                ProcessControl::CreateThread {
-                    ip,
-                    stack_size,
-                    extra_data,
-                } => {
-                    let ip = *ip as u64;
-                    let extra_data = *extra_data;
-                    let stack_size = *stack_size;
-                    let mut thread = self.new_empty_thread(stack_size).await;
-                    thread.setup_user_ip(ip);
-                    thread.setup_user_custom_data(extra_data);
-
+            numbers::FORK => match self.fork().await {
+                Ok(thread) => {
                     let thread_id = thread.thread_id;
+                    let process_id = thread.process().process_id();
                     crate::SCHEDULER
                         .spawn(2, crate::SPAWN_THREADS.get().unwrap().send((thread, 1)))
                         .detach();
-
-                    let syscall = self.get_syscall();
-                    *syscall.return_data = Sysrets::SuccessWithVal(thread_id as u64);
-                    syscall.return_data_awaiter.try_set_result(());
-                    Poll::Pending
+                    Ok((thread_id as u64, process_id as u64))
+                }
+                Err(reason) => {
+                    warn!("Fork failed for thread {}: {}", self.thread_id, reason);
+                    Err(SyscallError::Failed)
+                }
+            },
+            numbers::SPAWN => {
+                let path = UserSlice::<u8>::new(args[0], args[1] as usize);
+                let path = {
+                    let mut kpt = self.get_page_table().lock().await;
+                    path.read_to_string(&mut kpt)
+                };
+                match path {
+                    Ok(path) => self.spawn(&path).await,
+                    Err(_) => Err(SyscallError::InvalidArgument),
+                }
+            }
+            numbers::KILL => match process_table::get(args[0] as usize) {
+                Some(child) if child.parent_id() == Some(self.process().process_id()) => {
+                    child.kill(ExitStatus::Killed);
+                    Ok((0, 0))
                 }
+                _ => Err(SyscallError::InvalidArgument),
             },
+            numbers::WAIT => match self.process().child(args[0] as usize) {
+                Some(child) => {
+                    let status = child.wait().await;
+                    self.process().remove_child(child.process_id());
+                    Ok(exit_reason(status))
+                }
+                None => Err(SyscallError::InvalidArgument),
+            },
+            numbers::TRY_WAIT => match self.process().child(args[0] as usize) {
+                Some(child) => match child.exit_status() {
+                    Some(status) => {
+                        self.process().remove_child(child.process_id());
+                        Ok(exit_reason(status))
+                    }
+                    None => Ok((ExitReason::Running as u64, 0)),
+                },
+                None => Err(SyscallError::InvalidArgument),
+            },
+            numbers::JOIN_THREAD => match self.join(args[0] as usize).await {
+                Ok(ExitStatus::Exited(code)) => Ok((ExitKind::Exited as u64, code as u64)),
+                Ok(ExitStatus::PageFault(fault)) | Ok(ExitStatus::StackOverflow { fault, .. }) => {
+                    Ok((ExitKind::Killed as u64, fault.address))
+                }
+                Ok(ExitStatus::OutOfMemory) | Ok(ExitStatus::Killed) => {
+                    Ok((ExitKind::Killed as u64, 0))
+                }
+                Err(reason) => {
+                    warn!("Thread with id {} cannot join: {}", self.thread_id, reason);
+                    Err(SyscallError::InvalidArgument)
+                }
+            },
+            numbers::FUTEX_WAIT => self.futex_wait(args[0], args[1] as u32).await,
+            numbers::FUTEX_WAKE => self.futex_wake(args[0], args[1] as usize).await,
+            numbers::TIME_NOW => {
+                let now = crate::arch::time::now();
+                Ok((now.as_secs(), now.subsec_nanos() as u64))
+            }
+            numbers::SLEEP_FOR => {
+                if args[1] >= NANOS_PER_SEC {
+                    Err(SyscallError::InvalidArgument)
+                } else {
+                    time::sleep(Duration::new(args[0], args[1] as u32)).await;
+                    Ok((0, 0))
+                }
+            }
+            numbers::MMAP => match to_permissions(args[2]) {
+                Some(permissions) => {
+                    let mut kpt = self.get_page_table().lock().await;
+                    match kpt.map_anonymous(args[0], args[1] as usize, permissions) {
+                        Ok(start) => Ok((start, 0)),
+                        Err(kernel_page_table::INVALID_SIZE) => {
+                            Err(SyscallError::InvalidArgument)
+                        }
+                        Err(_) => Err(SyscallError::Failed),
+                    }
+                }
+                None => Err(SyscallError::InvalidArgument),
+            },
+            numbers::MUNMAP => {
+                let mut kpt = self.get_page_table().lock().await;
+                match kpt.unmap_anonymous(args[0], args[1] as usize) {
+                    Ok(()) => Ok((0, 0)),
+                    Err(_) => Err(SyscallError::InvalidArgument),
+                }
+            }
+            numbers::MPROTECT => match to_permissions(args[2]) {
+                Some(permissions) => {
+                    let mut kpt = self.get_page_table().lock().await;
+                    match kpt.protect_anonymous(args[0], args[1] as usize, permissions) {
+                        Ok(()) => Ok((0, 0)),
+                        Err(frame_allocator::ALLOCATION_FAILED) => Err(SyscallError::Failed),
+                        Err(_) => Err(SyscallError::InvalidArgument),
+                    }
+                }
+                None => Err(SyscallError::InvalidArgument),
+            },
+            _ => {
+                warn!("Thread with id {} made an unknown syscall {}", self.thread_id, number);
+                Err(SyscallError::UnknownSyscall)
+            }
+        };
+
+        let syscall = self.get_syscall();
+        syscall.registers.set_syscall_result(result);
+        syscall.return_data_awaiter.try_set_result(());
+        Poll::Pending
+    }
+
+    /// Start the program at `path` in a child process and schedule its main thread.
+    async fn spawn(&self, path: &str) -> SyscallResult {
+        let parent = self.process();
+        match spawn_process(path, &[], &[], DEFAULT_STACK_SIZE, Some(parent.process_id())).await {
+            Ok(thread) => {
+                let child = thread.process().clone();
+                let process_id = child.process_id();
+                let handle = parent.insert_handle(Handle::Process(child));
+                crate::SCHEDULER
+                    .spawn(2, crate::SPAWN_THREADS.get().unwrap().send((thread, 1)))
+                    .detach();
+                Ok((process_id as u64, handle))
+            }
+            Err(reason) => {
+                warn!("Thread with id {} cannot spawn {}: {}", self.thread_id, path, reason);
+                Err(SyscallError::Failed)
+            }
         }
     }
 
+    /// Block until the futex at `addr` is woken up if it contains `expected`.
+    async fn futex_wait(&self, addr: u64, expected: u32) -> SyscallResult {
+        let (key, wait) = {
+            let mut kpt = self.get_page_table().lock().await;
+            let (key, futex, value) = match kpt.get_futex(addr) {
+                Ok(futex) => futex,
+                Err(_) => return Err(SyscallError::InvalidArgument),
+            };
+            if value != expected {
+                kpt.release_futex(key);
+                return Ok((0, 0));
+            }
+
+            // Waking up takes the page table lock. So, the wake up cannot be missed once
+            // the value is checked.
+            (key, futex.wait())
+        };
+
+        wait.await;
+        self.get_page_table().lock().await.release_futex(key);
+        Ok((0, 0))
+    }
+
+    /// Wake up to `count` threads waiting on the futex at `addr`.
+    async fn futex_wake(&self, addr: u64, count: usize) -> SyscallResult {
+        let mut kpt = self.get_page_table().lock().await;
+        let (key, futex, _) = kpt
+            .get_futex(addr)
+            .map_err(|_| SyscallError::InvalidArgument)?;
+        let woken = futex.wake(count);
+        kpt.release_futex(key);
+        Ok((woken as u64, 0))
+    }
+
     fn get_syscall(&mut self) -> &mut SyscallState {
         let state = &mut self.state;
         match state {
@@ -100,3 +273,33 @@ impl Thread {
         }
     }
 }
+
+/// Convert the exit status of a process to the [`ExitReason`] and the value returned by
+/// [`numbers::WAIT`].
+fn exit_reason(status: ExitStatus) -> (u64, u64) {
+    match status {
+        ExitStatus::Exited(code) => (ExitReason::Exited as u64, code as u64),
+        ExitStatus::PageFault(fault) => (ExitReason::PageFault as u64, fault.address),
+        ExitStatus::StackOverflow { fault, .. } => {
+            (ExitReason::StackOverflow as u64, fault.address)
+        }
+        ExitStatus::OutOfMemory => (ExitReason::OutOfMemory as u64, 0),
+        ExitStatus::Killed => (ExitReason::Killed as u64, 0),
+    }
+}
+
+/// Convert the [`protection`] flags of a syscall to permissions for user memory.
+fn to_permissions(flags: u64) -> Option<MapperPermissions> {
+    if flags & !(protection::WRITE | protection::EXECUTE) != 0 {
+        return None;
+    }
+
+    let mut permissions = MapperPermissions::READ | MapperPermissions::RING_3;
+    if flags & protection::WRITE != 0 {
+        permissions |= MapperPermissions::WRITE;
+    }
+    if flags & protection::EXECUTE != 0 {
+        permissions |= MapperPermissions::EXECUTE;
+    }
+    Some(permissions)
+}
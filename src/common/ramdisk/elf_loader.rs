@@ -1,11 +1,12 @@
 //! The default ELF loader for the kernel.
 
+use alloc::vec::Vec;
 use elfloader::{ElfLoader, Flags, LoadableHeaders, Rela, TypeRela64, VAddr, P64};
 
 use crate::arch::globals;
 pub use crate::common;
 
-use self::common::memory::paging::{IMemoryMapper, MapperPermissions};
+use self::common::memory::paging::{IMemoryMapper, MapperHints, MapperPermissions};
 
 /// Default ELF loader class. Can load ELF onto address space
 /// defined by the [mapper].
@@ -14,6 +15,8 @@ pub struct DefaultElfLoader<'a> {
     mapper: &'a mut dyn IMemoryMapper,
 
     last_exe_section_location: u64,
+    /// File offset, virtual address and file size of the loaded segments.
+    segments: Vec<(u64, u64, u64)>,
 }
 
 impl<'a> DefaultElfLoader<'a> {
@@ -22,6 +25,7 @@ impl<'a> DefaultElfLoader<'a> {
             vbase,
             mapper,
             last_exe_section_location: 0,
+            segments: Vec::new(),
         }
     }
 
@@ -30,6 +34,14 @@ impl<'a> DefaultElfLoader<'a> {
     pub fn get_exe_location(&self) -> u64 {
         self.last_exe_section_location
     }
+
+    /// Get the virtual address where the data at `offset` in the file is loaded.
+    pub fn file_offset_to_vaddr(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(start, _, size)| (*start..*start + *size).contains(&offset))
+            .map(|(start, vaddr, _)| self.vbase + vaddr + (offset - start))
+    }
 }
 
 /// Implement this trait for customized ELF loading.
@@ -51,6 +63,9 @@ impl<'a> ElfLoader for DefaultElfLoader<'a> {
                 header.flags()
             );
 
+            self.segments
+                .push((header.offset(), header.virtual_addr(), header.file_size()));
+
             let virt_addr_to_load_at = header.virtual_addr() as usize;
             let virt_addr_to_load_at_page_aligned =
                 common::align_down(virt_addr_to_load_at, globals::PAGE_SIZE);
@@ -75,6 +90,7 @@ impl<'a> ElfLoader for DefaultElfLoader<'a> {
                 virt_addr_to_load_at_page_aligned as *const u8,
                 end_vaddr_to_load_at_aligned - virt_addr_to_load_at_page_aligned,
                 target_permissions,
+                MapperHints::empty(),
             )?;
 
             // Zero the data
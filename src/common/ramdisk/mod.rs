@@ -4,3 +4,15 @@
 
 pub mod elf_loader;
 pub mod ustar;
+
+use crate::arch::globals;
+use ustar::UStarArchive;
+
+/// Get the initial ram disk loaded by the boot loader.
+pub fn initrd() -> UStarArchive {
+    unsafe {
+        let initrd_ptr =
+            (crate::bootboot::bootboot.initrd_ptr + globals::MEM_MAP_OFFSET_LOCATION) as *const u8;
+        UStarArchive::new(initrd_ptr, crate::bootboot::bootboot.initrd_size as usize)
+    }
+}
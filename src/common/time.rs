@@ -0,0 +1,88 @@
+//! Timers for kernel tasks. Sleeping tasks are kept in a queue ordered by their deadline and
+//! are woken up once the deadline passes. See [`wake_expired_timers`].
+
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use futures_lite::Future;
+
+use crate::arch::time::now;
+
+/// Wakers of the sleeping tasks keyed by the deadline and an unique id of the sleep.
+static TIMERS: spin::Mutex<BTreeMap<(Duration, usize), Waker>> =
+    spin::Mutex::new(BTreeMap::new());
+
+static NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Wake up the tasks whose deadline has passed. This is called regularly by the scheduler.
+/// It is not called from interrupts because waking up a task can take executor locks.
+pub fn wake_expired_timers() {
+    let now = now();
+    let mut expired = Vec::new();
+    {
+        let mut timers = TIMERS.lock();
+        while let Some(entry) = timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            expired.push(entry.remove());
+        }
+    }
+
+    for waker in expired {
+        waker.wake();
+    }
+}
+
+/// Sleep until the monotonic clock reaches `deadline`.
+pub fn sleep_until(deadline: Duration) -> Sleep {
+    Sleep {
+        deadline,
+        id: NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+/// Sleep for the given duration. A deadline past the end of the clock never passes.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(now().saturating_add(duration))
+}
+
+/// A future that completes once the deadline has passed.
+pub struct Sleep {
+    deadline: Duration,
+    id: usize,
+    registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let key = (self.deadline, self.id);
+        if now() >= self.deadline {
+            if self.registered {
+                TIMERS.lock().remove(&key);
+                self.registered = false;
+            }
+            return Poll::Ready(());
+        }
+
+        TIMERS.lock().insert(key, cx.waker().clone());
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            TIMERS.lock().remove(&(self.deadline, self.id));
+        }
+    }
+}
@@ -21,13 +21,11 @@
 #![feature(thread_local)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
-use arch::{globals, memory::kernel_page_table::KernelPageTable, process::Thread};
-use common::ramdisk::{elf_loader::DefaultElfLoader, ustar::UStarArchive};
+use arch::process::Thread;
 use core::{
     panic::PanicInfo,
     sync::atomic::{AtomicUsize, Ordering},
 };
-use elfloader::ElfBinary;
 use logging::UnifiedLogger;
 use moondust_utils::{
     executor::priority_executor::PriorityExecutor,
@@ -80,22 +78,34 @@ fn _start() -> ! {
     }
 }
 
-pub static SCHEDULER: PriorityExecutor<5> = PriorityExecutor::const_new();
+/// Number of tasks with better priorities that are run before a waiting task gets its turn.
+/// This keeps user threads running even when kernel tasks are busy.
+const SCHEDULER_AGING_LIMIT: usize = 16;
+
+/// Number of priorities of the scheduler. Tasks are spawned with a priority below this.
+pub const SCHEDULER_PRIORITIES: usize = 5;
+
+/// The scheduler shared by all the cores. Each core has its own run queues.
+pub static SCHEDULER: PriorityExecutor<SCHEDULER_PRIORITIES> =
+    PriorityExecutor::const_new(arch::cpu_locals::cpu_index, SCHEDULER_AGING_LIMIT);
 
 /// Main function on AP Processor.
+/// All the cores run the same scheduler. So, tasks and user threads can run on any core.
 pub fn main_app() -> ! {
-    x86_64::instructions::interrupts::enable();
-    loop {}
+    arch::process::block_on(SCHEDULER.run())
 }
 
 /// Main Function on bootstrap processor.
 /// This function should not return.
 pub fn main_bsp() -> ! {
+    #[cfg(feature = "self-checks")]
+    arch::memory::self_checks::run();
+
     // Thread spawner is used to spawn new threads onto the scheduler.
     SCHEDULER.spawn(2, thread_spawner()).detach();
 
-    // Load the main process.
-    SCHEDULER.spawn(2, load_alpha()).detach();
+    // Start the programs listed in the init manifest.
+    SCHEDULER.spawn(2, common::process::init::run()).detach();
 
     // x86_64::instructions::interrupts::enable();
     arch::process::block_on(SCHEDULER.run())
@@ -114,46 +124,6 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
-async fn load_alpha() {
-    const STACK_SIZE: usize = 10 * 4096 * 1024;
-
-    {
-        let mut thread = Thread::new_empty_process(STACK_SIZE).await;
-        thread.activate().await;
-
-        let ramdisk: UStarArchive;
-        unsafe {
-            let initrd_ptr =
-                (bootboot::bootboot.initrd_ptr + globals::MEM_MAP_OFFSET_LOCATION) as *const u8;
-            ramdisk = UStarArchive::new(initrd_ptr, bootboot::bootboot.initrd_size as usize);
-            info!(target: "load_alpha", "Initrd image is {}", ramdisk);
-        }
-
-        let file_name = "./userspace/moondust-alpha";
-        let file = ramdisk.lookup(file_name).expect("Alpha file not found");
-        let binary = ElfBinary::new("moondust-alpha", file).expect("Cannot read the binary");
-
-        {
-            let mut pt = thread.get_page_table().lock().await;
-            let mut loader = DefaultElfLoader::new(0x0, &mut pt as &mut KernelPageTable);
-            binary.load(&mut loader).expect("Binary loading failed");
-            info!(target: "load_alpha", 
-            "Alpha project loaded. Use comand `add-symbol-file ../../x86_64-moondust-user/debug/moondust-alpha  0x{:x}`", 
-            loader.get_exe_location());
-        }
-
-        {
-            let entry_point = binary.entry_point() as *const ();
-            thread.setup_user_ip(entry_point as u64);
-        }
-        let result = SCHEDULER.spawn(4, thread.run_thread()).await;
-        info!("Alpha process exited with return status: {}", result);
-    }
-
-    let mut thread = Thread::new_empty_process(2 * 4096).await;
-    thread.activate().await;
-}
-
 /// Channel that will spawn new threads onto the scheduler.
 pub static SPAWN_THREADS: Once<Sender<(Thread, usize)>> = Once::new();
 
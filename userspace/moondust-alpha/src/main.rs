@@ -9,6 +9,9 @@ extern crate alloc;
 #[no_mangle]
 pub fn main() {
     debug_print!("Syscall!");
+    for arg in std::env::args() {
+        debug_print!("Argument: {}", arg);
+    }
 
     let a = alloc::boxed::Box::new(10u8);
     debug_print!("Test val: {}", a);